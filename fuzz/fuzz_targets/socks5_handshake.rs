@@ -0,0 +1,88 @@
+// Feeds arbitrary client bytes into the SOCKS5 handshake. The first byte picks the server
+// configuration and the second is the method count that follows the version byte; the
+// rest is what the client sends. The handshake must fail cleanly or yield a well-formed
+// request, and never panic.
+
+#![no_main]
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use libfuzzer_sys::fuzz_target;
+use musocks::{socks5, Address, AuthPolicy, Connector, Server, StaticAuthenticator, Upstream};
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+
+// RefusingConnector keeps CONNECT off the network; the parser is what is under test.
+struct RefusingConnector;
+
+#[async_trait]
+impl Connector for RefusingConnector {
+    async fn connect(&self, _: &Address, _: u16, _: &Server) -> musocks::Result<TcpStream> {
+        Err(io::Error::from(io::ErrorKind::ConnectionRefused).into())
+    }
+}
+
+struct Fixture {
+    runtime: Runtime,
+    // Without authentication, and with username/password required.
+    servers: [Server; 2],
+}
+
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| {
+        let builder = || {
+            Server::builder()
+                .connector(Arc::new(RefusingConnector))
+                // BIND would otherwise wait for an inbound connection.
+                .bind_timeout(Duration::ZERO)
+        };
+        let users = HashMap::from([(b"user".to_vec(), b"password".to_vec())]);
+        Fixture {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap(),
+            servers: [
+                builder().build(),
+                builder()
+                    .authenticator(Arc::new(StaticAuthenticator::new(users)))
+                    .auth_policy(AuthPolicy::RequireAuth)
+                    .build(),
+            ],
+        }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let [config, n_auth, input @ ..] = data else {
+        return;
+    };
+    let mut input = input;
+    let fixture = fixture();
+    let server = &fixture.servers[*config as usize % fixture.servers.len()];
+    let mut output = tokio::io::sink();
+    let result =
+        fixture
+            .runtime
+            .block_on(socks5::handshake(&mut input, &mut output, *n_auth, server));
+    let Ok((request, upstream)) = result else {
+        return;
+    };
+
+    if let Address::Domain(_) = &request.address {
+        let domain = request.address.domain().expect("domain is not utf-8");
+        assert!(
+            Address::try_from(domain).is_ok(),
+            "invalid domain {domain:?}"
+        );
+    }
+    match (request.command, upstream) {
+        (0x01 | 0x02, Upstream::Tcp(_)) | (0x03, Upstream::Udp(_)) => {}
+        (command, _) => panic!("command {command:#04x} succeeded with the wrong upstream"),
+    }
+});
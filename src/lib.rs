@@ -0,0 +1,23 @@
+//! musocks is a small SOCKS4/SOCKS5 proxy server.
+//!
+//! [`Server`] runs a complete proxy that also accepts HTTP CONNECT on the same port. The
+//! handshake functions in [`socks4`], [`socks5`] and [`http`] can be used on their own to
+//! build a custom proxy on top of the same protocol logic, and [`proxy_connection`] serves
+//! a single connection accepted by the caller.
+
+mod socks;
+
+pub use socks::{
+    http, proxy_connection, socks4, socks5, verify_password, Acl, AclLoader, Address,
+    AddressFamily, AllowAnonymous, AllowedIdents, Auth, AuthPolicy, AuthResult, Authenticator,
+    BandwidthLimit, BandwidthScope, ByteBuf, ClientAddr, ClientSubnet, ConnIdFormat, Connector,
+    Destination, DirectConnector, DnsCache, DomainMatcher, Error, FileAuthenticator,
+    IdentAuthorizer, Keepalive, ListenAddr, LockoutPolicy, Metrics, NameserverResolver,
+    OverloadPolicy, PortPolicy, PortRange, RateLimit, Request, Resolver, Result, Server,
+    ServerBuilder, SessionStats, StaticAuthenticator, SystemResolver, Upstream, UpstreamProxy,
+};
+
+#[cfg(feature = "tls")]
+pub use socks::Tls;
+#[cfg(feature = "doh")]
+pub use socks::{DohEndpoint, DohResolver};
@@ -1,18 +1,970 @@
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use ipnet::IpNet;
+use musocks::{
+    Acl, AddressFamily, AllowedIdents, AuthPolicy, BandwidthLimit, BandwidthScope, ClientSubnet,
+    ConnIdFormat, DnsCache, DomainMatcher, Keepalive, ListenAddr, LockoutPolicy,
+    NameserverResolver, OverloadPolicy, PortPolicy, PortRange, RateLimit, StaticAuthenticator,
+    UpstreamProxy,
+};
+use serde::Deserialize;
 use slog::Drain;
 
-mod socks;
+// Crate version and the git commit it was built from.
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("MUSOCKS_GIT_SHA"),
+    ")"
+);
+
+#[derive(Parser, Clone)]
+#[command(about = "A SOCKS4/SOCKS5 and HTTP CONNECT proxy server", version = VERSION)]
+struct Args {
+    /// TOML configuration file; command line options override its values. SIGHUP
+    /// re-reads the access rules from it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Log output format: text or json [default: text]
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+
+    /// File to append one record per finished session to; disabled if omitted
+    #[arg(long)]
+    access_log: Option<PathBuf>,
+
+    /// How connection IDs in the logs are made: counter or uuid [default: counter]
+    #[arg(long)]
+    conn_id: Option<ConnIdFormat>,
+
+    /// Look up the host name of each client and log it next to its address
+    #[arg(long, overrides_with = "no_reverse_dns")]
+    reverse_dns: bool,
+
+    /// Turn off --reverse-dns, overriding the config file
+    #[arg(long, overrides_with = "reverse_dns")]
+    no_reverse_dns: bool,
+
+    /// Address to listen on: ADDR:PORT, tcp://ADDR:PORT or unix:///PATH. Repeat to listen
+    /// on several addresses [default: 0.0.0.0:1080]
+    #[arg(long)]
+    listen: Vec<ListenAddr>,
+
+    /// Keep running when some listen addresses fail to bind, as long as one binds
+    #[arg(long, overrides_with = "no_ignore_bind_failures")]
+    ignore_bind_failures: bool,
+
+    /// Turn off --ignore-bind-failures, overriding the config file
+    #[arg(long, overrides_with = "ignore_bind_failures")]
+    no_ignore_bind_failures: bool,
+
+    /// Accept only IPv6 clients on an IPv6 listen address such as [::]:1080
+    #[arg(long, overrides_with = "no_ipv6_only")]
+    ipv6_only: bool,
+
+    /// Turn off --ipv6-only, overriding the config file
+    #[arg(long, overrides_with = "ipv6_only")]
+    no_ipv6_only: bool,
+
+    /// Set SO_REUSEPORT so that several instances can listen on the same port
+    #[arg(long, overrides_with = "no_reuse_port")]
+    reuse_port: bool,
+
+    /// Turn off --reuse-port, overriding the config file
+    #[arg(long, overrides_with = "reuse_port")]
+    no_reuse_port: bool,
+
+    /// Length of the queue of connections waiting to be accepted; the OS caps it at
+    /// net.core.somaxconn (kern.ipc.somaxconn on BSD and macOS) [default: 1024]
+    #[arg(long)]
+    listen_backlog: Option<u32>,
+
+    /// Timeout for connecting to upstream (e.g. "10s"); unlimited if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// IP families to connect to upstream over: auto, ipv4-only, ipv6-only, prefer-ipv4
+    /// or prefer-ipv6; SOCKS5 requests for a literal of a disabled family are rejected
+    /// [default: auto]
+    #[arg(long)]
+    address_family: Option<AddressFamily>,
+
+    /// Resolve domains by querying this DNS server (e.g. 9.9.9.9:53) instead of the
+    /// system resolver
+    #[arg(long)]
+    nameserver: Option<std::net::SocketAddr>,
+
+    /// Pass clients' subnets to --nameserver with EDNS Client Subnet, truncated to these
+    /// IPv4 and IPv6 prefix lengths (e.g. "24,56"); clients with private addresses are
+    /// never revealed
+    #[arg(long)]
+    client_subnet: Option<ClientSubnet>,
+
+    /// Cache resolved domain names for this long (e.g. "1m"); disabled if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    dns_cache_ttl: Option<Duration>,
+
+    /// Cache failed lookups for this long [default: 5s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    dns_cache_negative_ttl: Option<Duration>,
+
+    /// Most domain names kept in the DNS cache [default: 1024]
+    #[arg(long)]
+    dns_cache_size: Option<usize>,
+
+    /// SOCKS5 proxy to forward CONNECT requests through, e.g. 10.0.0.1:1080
+    #[arg(long)]
+    upstream_proxy: Option<std::net::SocketAddr>,
+
+    /// Never resolve requested domains; pass them to --upstream-proxy verbatim. The
+    /// resolved destination IP is then missing from logs
+    #[arg(long, overrides_with = "no_remote_dns")]
+    remote_dns: bool,
+
+    /// Turn off --remote-dns, overriding the config file
+    #[arg(long, overrides_with = "remote_dns")]
+    no_remote_dns: bool,
+
+    /// Local address to connect to upstream and relay UDP from; may be given once per IP
+    /// family
+    #[arg(long = "source-ip")]
+    source_ips: Vec<std::net::IpAddr>,
+
+    /// Port range to connect to upstream from, e.g. 40000-40999; an ephemeral port is
+    /// used if omitted
+    #[arg(long)]
+    source_ports: Option<PortRange>,
+
+    /// Network interface to connect to upstream through, e.g. wg0. Linux only; needs
+    /// CAP_NET_RAW
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// Send a PROXY protocol v2 header with the client address to upstream
+    #[arg(long, overrides_with = "no_send_proxy_protocol")]
+    send_proxy_protocol: bool,
+
+    /// Turn off --send-proxy-protocol, overriding the config file
+    #[arg(long, overrides_with = "send_proxy_protocol")]
+    no_send_proxy_protocol: bool,
+
+    /// How long a BIND request waits for the inbound connection [default: 60s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    bind_timeout: Option<Duration>,
+
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100; disabled if omitted
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Address to serve the admin API on, e.g. 127.0.0.1:9200; needs admin_token in the
+    /// config file; disabled if omitted
+    #[cfg(feature = "admin")]
+    #[arg(long)]
+    admin_listen: Option<std::net::SocketAddr>,
+
+    /// Address answering load balancer health checks with `OK` or an HTTP 200 on
+    /// /healthz, e.g. 0.0.0.0:8081; disabled if omitted
+    #[arg(long)]
+    health_listen: Option<std::net::SocketAddr>,
+
+    /// Close new connections while draining (toggled by SIGUSR1) instead of only
+    /// failing the health check
+    #[arg(long, overrides_with = "no_reject_while_draining")]
+    reject_while_draining: bool,
+
+    /// Turn off --reject-while-draining, overriding the config file
+    #[arg(long, overrides_with = "reject_while_draining")]
+    no_reject_while_draining: bool,
+
+    /// PEM certificate chain; clients must then connect over TLS
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// DNS-over-HTTPS URL to resolve domains with instead of the system resolver, e.g.
+    /// https://dns.quad9.net/dns-query
+    #[cfg(feature = "doh")]
+    #[arg(long)]
+    doh_endpoint: Option<musocks::DohEndpoint>,
+
+    /// IP address to reach --doh-endpoint at without resolving its host name
+    #[cfg(feature = "doh")]
+    #[arg(long)]
+    doh_bootstrap: Option<std::net::IpAddr>,
+
+    /// PEM file with the CAs to trust for --doh-endpoint [default: the system CA bundle]
+    #[cfg(feature = "doh")]
+    #[arg(long)]
+    doh_ca_file: Option<PathBuf>,
+
+    /// Idle time before TCP keepalive probes are sent (e.g. "60s"); enables keepalive
+    #[arg(long, value_parser = humantime::parse_duration)]
+    keepalive_time: Option<Duration>,
+
+    /// Time between TCP keepalive probes; enables keepalive [default: OS setting]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    keepalive_interval: Option<Duration>,
+
+    /// Unanswered TCP keepalive probes before dropping; enables keepalive
+    /// [default: OS setting]
+    #[arg(long)]
+    keepalive_retries: Option<u32>,
+
+    /// Close a session when neither side sends data for this long; unlimited if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Close a session when a single write to either side blocks for this long, as it
+    /// does when a peer stops reading; unlimited if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    write_timeout: Option<Duration>,
+
+    /// Close a session after this long regardless of activity; unlimited if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_session_duration: Option<Duration>,
+
+    /// Bytes buffered per direction of each session; larger values use more memory but
+    /// may help on fast links [default: 8192]
+    #[arg(long)]
+    relay_buffer_size: Option<usize>,
+
+    /// Bytes per second each session may transfer; unlimited if omitted
+    #[arg(long)]
+    bandwidth_limit: Option<u64>,
+
+    /// Bytes per second all sessions together may transfer; unlimited if omitted
+    #[arg(long)]
+    global_bandwidth_limit: Option<u64>,
+
+    /// Whether the bandwidth limit applies to each direction or to both together:
+    /// per-direction or combined [default: per-direction]
+    #[arg(long)]
+    bandwidth_scope: Option<BandwidthScope>,
+
+    /// Log the bytes relayed so far at this interval (e.g. "30s"); disabled if omitted
+    #[arg(long, value_parser = humantime::parse_duration)]
+    progress_interval: Option<Duration>,
+
+    /// Time allowed for SOCKS5 authentication [default: 10s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    auth_timeout: Option<Duration>,
+
+    /// Time allowed from accepting a client until it has sent its whole request,
+    /// authentication included [default: 10s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    handshake_timeout: Option<Duration>,
+
+    /// File of `username:password_hash` lines for username/password authentication
+    #[arg(long)]
+    credentials_file: Option<PathBuf>,
+
+    /// Method selection policy: require-auth, prefer-auth or prefer-anonymous
+    /// [default: prefer-auth]
+    #[arg(long)]
+    auth_policy: Option<AuthPolicy>,
+
+    /// How long to wait for active connections on shutdown [default: 30s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    drain_timeout: Option<Duration>,
+
+    /// Maximum number of concurrent connections; unlimited if omitted
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// What to do with new connections at the limit: wait or reject [default: wait]
+    #[arg(long)]
+    overload_policy: Option<OverloadPolicy>,
+
+    /// Number of concurrent connections beyond which new ones are accepted more slowly;
+    /// requires --max-connections
+    #[arg(long)]
+    soft_max_connections: Option<usize>,
+
+    /// How long each accept is delayed beyond --soft-max-connections [default: 100ms]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    backpressure_delay: Option<Duration>,
+
+    /// Refuse to connect to loopback, private and link-local destinations. With
+    /// --upstream-proxy, only IP address destinations are checked, since the proxy resolves
+    /// domains
+    #[arg(long, overrides_with = "no_block_private_destinations")]
+    block_private_destinations: bool,
+
+    /// Turn off --block-private-destinations, overriding the config file
+    #[arg(long, overrides_with = "block_private_destinations")]
+    no_block_private_destinations: bool,
+
+    /// Client network allowed to connect (e.g. 192.168.0.0/16); may be repeated.
+    /// Everyone is allowed if omitted
+    #[arg(long = "allow-client")]
+    allow_clients: Vec<IpNet>,
+
+    /// Answer failed requests with a generic error after a random delay, hiding which
+    /// hosts and ports are reachable, except to trusted clients
+    #[arg(long, overrides_with = "no_stealth_errors")]
+    stealth_errors: bool,
+
+    /// Turn off --stealth-errors, overriding the config file
+    #[arg(long, overrides_with = "stealth_errors")]
+    no_stealth_errors: bool,
+
+    /// Client network that gets detailed errors with --stealth-errors; may be repeated
+    #[arg(long = "trusted-client")]
+    trusted_clients: Vec<IpNet>,
+
+    /// SOCKS4 ident allowed to connect; may be repeated. Every ident is allowed if omitted
+    #[arg(long = "allow-ident")]
+    allow_idents: Vec<String>,
+
+    /// Destination port clients may connect to (can be repeated)
+    #[arg(long = "allow-port", conflicts_with = "deny_ports")]
+    allow_ports: Vec<u16>,
+
+    /// Destination port clients may not connect to (can be repeated)
+    #[arg(long = "deny-port")]
+    deny_ports: Vec<u16>,
+
+    /// Hostname clients may not connect to, exact or `*.example.com` (can be repeated)
+    #[arg(long = "deny-domain")]
+    deny_domains: Vec<String>,
+
+    /// Lock out a client IP after this many failed logins
+    #[arg(long)]
+    lockout_max_failures: Option<u32>,
+
+    /// Window in which failed logins are counted [default: 5m]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    lockout_window: Option<Duration>,
+
+    /// How long a locked-out client IP is rejected [default: 15m]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    lockout_cooldown: Option<Duration>,
+
+    /// New connections per second allowed from a single client IP
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Connections a client IP may open in a burst above the rate [default: rate]
+    #[arg(long)]
+    rate_limit_burst: Option<u32>,
+}
+
+#[derive(Clone, Copy)]
+enum LogFormat {
+    // Human-readable lines from slog-term.
+    Text,
+    // One JSON object per event.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format `{s}` (expected text or json)")),
+        }
+    }
+}
+
+// Config is the content of the TOML configuration file.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    // One listen address, or an array of them.
+    listen: Option<ListenConfig>,
+    #[serde(default)]
+    ignore_bind_failures: bool,
+    log_format: Option<String>,
+    access_log: Option<PathBuf>,
+    conn_id: Option<String>,
+    #[serde(default)]
+    reverse_dns: bool,
+    #[serde(default)]
+    ipv6_only: bool,
+    #[serde(default)]
+    reuse_port: bool,
+    listen_backlog: Option<u32>,
+    #[serde(default)]
+    block_private_destinations: bool,
+    #[serde(default)]
+    stealth_errors: bool,
+    #[serde(default)]
+    send_proxy_protocol: bool,
+    // Local addresses to connect to upstream from, at most one per IP family.
+    #[serde(default)]
+    source_ips: Vec<std::net::IpAddr>,
+    source_ports: Option<String>,
+    interface: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    connect_timeout: Option<Duration>,
+    address_family: Option<String>,
+    nameserver: Option<std::net::SocketAddr>,
+    client_subnet: Option<String>,
+    #[serde(default)]
+    dns_cache: DnsCacheConfig,
+    #[serde(default, with = "humantime_serde")]
+    bind_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    write_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    handshake_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    max_session_duration: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    progress_interval: Option<Duration>,
+    relay_buffer_size: Option<usize>,
+    #[serde(default)]
+    bandwidth: BandwidthConfig,
+    #[serde(default, with = "humantime_serde")]
+    drain_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    soft_max_connections: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    backpressure_delay: Option<Duration>,
+    #[cfg(feature = "metrics")]
+    metrics_listen: Option<std::net::SocketAddr>,
+    #[cfg(feature = "admin")]
+    admin_listen: Option<std::net::SocketAddr>,
+    // Only read from the config file, so that it does not show up in the process list.
+    #[cfg(feature = "admin")]
+    admin_token: Option<String>,
+    health_listen: Option<std::net::SocketAddr>,
+    #[serde(default)]
+    reject_while_draining: bool,
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    tls: TlsConfig,
+    #[cfg(feature = "doh")]
+    #[serde(default)]
+    doh: DohConfig,
+    overload_policy: Option<String>,
+    #[serde(default)]
+    auth: AuthConfig,
+    #[serde(default)]
+    lockout: LockoutConfig,
+    #[serde(default)]
+    acl: AclConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    keepalive: KeepaliveConfig,
+    #[serde(default)]
+    upstream_proxy: UpstreamProxyConfig,
+    #[serde(default)]
+    remote_dns: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AuthConfig {
+    policy: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    credentials_file: Option<PathBuf>,
+    // Plaintext username/password pairs.
+    #[serde(default)]
+    users: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AclConfig {
+    // Client networks allowed to connect.
+    #[serde(default)]
+    allow: Vec<IpNet>,
+    // Client networks that get detailed errors with `stealth_errors`.
+    #[serde(default)]
+    trusted: Vec<IpNet>,
+    // SOCKS4 idents allowed to connect.
+    #[serde(default)]
+    allow_idents: Vec<String>,
+    // Destination ports; at most one of these may be set.
+    #[serde(default)]
+    allow_ports: Vec<u16>,
+    #[serde(default)]
+    deny_ports: Vec<u16>,
+    // Hostnames clients may not connect to, exact or `*.example.com`.
+    #[serde(default)]
+    deny_domains: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LockoutConfig {
+    max_failures: Option<u32>,
+    #[serde(default, with = "humantime_serde")]
+    window: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    cooldown: Option<Duration>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    rate: Option<f64>,
+    burst: Option<u32>,
+}
+
+#[cfg(feature = "tls")]
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[cfg(feature = "doh")]
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DohConfig {
+    endpoint: Option<String>,
+    bootstrap: Option<std::net::IpAddr>,
+    ca_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DnsCacheConfig {
+    #[serde(default, with = "humantime_serde")]
+    ttl: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    negative_ttl: Option<Duration>,
+    size: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BandwidthConfig {
+    // Bytes per second for each session.
+    limit: Option<u64>,
+    scope: Option<String>,
+    // Bytes per second for all sessions together.
+    global_limit: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct KeepaliveConfig {
+    #[serde(default, with = "humantime_serde")]
+    time: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    retries: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct UpstreamProxyConfig {
+    addr: Option<std::net::SocketAddr>,
+    // Credentials for the upstream proxy; both or neither must be set.
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenConfig {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ListenConfig {
+    fn parse(&self) -> anyhow::Result<Vec<ListenAddr>> {
+        let addrs = match self {
+            ListenConfig::One(addr) => std::slice::from_ref(addr),
+            ListenConfig::Many(addrs) => addrs.as_slice(),
+        };
+        addrs
+            .iter()
+            .map(|addr| addr.parse().map_err(anyhow::Error::msg))
+            .collect()
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let logger = setup_logger();
-    let server = socks::Server {
-        logger: logger.clone(),
+    let args = Args::parse();
+    let config = match &args.config {
+        Some(path) => load_config(path)?,
+        None => Config::default(),
+    };
+    let log_format =
+        or_parse(args.log_format, config.log_format.as_deref())?.unwrap_or(LogFormat::Text);
+    let logger = setup_logger(log_format, std::io::stderr());
+    slog::info!(logger, "musocks starting";
+        "version" => env!("CARGO_PKG_VERSION"),
+        "git_sha" => env!("MUSOCKS_GIT_SHA"));
+
+    let mut builder = musocks::Server::builder()
+        .logger(logger)
+        .ipv6_only(flag(args.ipv6_only, args.no_ipv6_only).unwrap_or(config.ipv6_only))
+        .reuse_port(flag(args.reuse_port, args.no_reuse_port).unwrap_or(config.reuse_port))
+        .ignore_bind_failures(
+            flag(args.ignore_bind_failures, args.no_ignore_bind_failures)
+                .unwrap_or(config.ignore_bind_failures),
+        )
+        .reverse_dns(flag(args.reverse_dns, args.no_reverse_dns).unwrap_or(config.reverse_dns))
+        .reject_while_draining(
+            flag(args.reject_while_draining, args.no_reject_while_draining)
+                .unwrap_or(config.reject_while_draining),
+        )
+        .acl(acl(&args, &config)?)
+        .send_proxy_protocol(
+            flag(args.send_proxy_protocol, args.no_send_proxy_protocol)
+                .unwrap_or(config.send_proxy_protocol),
+        );
+    if let Some(path) = args.config.clone() {
+        let args = args.clone();
+        builder = builder.acl_loader(Arc::new(move || acl(&args, &load_config(&path)?)));
+    }
+    if let Some(path) = args.access_log.or(config.access_log) {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("failed to open {}: {e}", path.display()))?;
+        builder = builder.access_logger(setup_logger(log_format, file));
+    }
+    if let Some(format) = or_parse(args.conn_id, config.conn_id.as_deref())? {
+        builder = builder.conn_id_format(format);
+    }
+    if !args.listen.is_empty() {
+        builder = builder.listen_addrs(args.listen);
+    } else if let Some(listen) = &config.listen {
+        builder = builder.listen_addrs(listen.parse()?);
+    }
+    if let Some(timeout) = args.connect_timeout.or(config.connect_timeout) {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(family) = or_parse(args.address_family, config.address_family.as_deref())? {
+        builder = builder.address_family(family);
+    }
+    let nameserver = args.nameserver.or(config.nameserver);
+    let client_subnet = or_parse(args.client_subnet, config.client_subnet.as_deref())?;
+    match (nameserver, client_subnet) {
+        (Some(addr), client_subnet) => {
+            let mut resolver = NameserverResolver::new(addr);
+            if let Some(client_subnet) = client_subnet {
+                resolver = resolver.client_subnet(client_subnet);
+            }
+            builder = builder.resolver(Arc::new(resolver));
+        }
+        // The system resolver has no way to pass the subnet on.
+        (None, Some(_)) => anyhow::bail!("client_subnet requires nameserver"),
+        (None, None) => {}
+    }
+    #[cfg(feature = "doh")]
+    if let Some(endpoint) = or_parse(args.doh_endpoint, config.doh.endpoint.as_deref())? {
+        if nameserver.is_some() {
+            anyhow::bail!("nameserver and doh.endpoint cannot be used together");
+        }
+        let ca_file = args.doh_ca_file.or(config.doh.ca_file);
+        let mut resolver = musocks::DohResolver::new(endpoint, ca_file.as_deref())
+            .map_err(|e| anyhow::anyhow!("failed to set up DoH: {e}"))?;
+        if let Some(ip) = args.doh_bootstrap.or(config.doh.bootstrap) {
+            resolver = resolver.bootstrap(ip);
+        }
+        builder = builder.resolver(Arc::new(resolver));
+    }
+    if let Some(ttl) = args.dns_cache_ttl.or(config.dns_cache.ttl) {
+        let capacity = args
+            .dns_cache_size
+            .or(config.dns_cache.size)
+            .unwrap_or(1024);
+        if capacity == 0 {
+            anyhow::bail!("DNS cache size must be positive");
+        }
+        builder = builder.dns_cache(DnsCache {
+            capacity,
+            ttl,
+            negative_ttl: (args.dns_cache_negative_ttl)
+                .or(config.dns_cache.negative_ttl)
+                .unwrap_or(Duration::from_secs(5)),
+        });
+    }
+    if let Some(addr) = args.upstream_proxy.or(config.upstream_proxy.addr) {
+        let credentials = match (
+            config.upstream_proxy.username,
+            config.upstream_proxy.password,
+        ) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "upstream_proxy.username and upstream_proxy.password must be set together"
+            ),
+        };
+        builder = builder.upstream_proxy(UpstreamProxy { addr, credentials });
+    } else if flag(args.remote_dns, args.no_remote_dns).unwrap_or(config.remote_dns) {
+        anyhow::bail!("remote_dns requires upstream_proxy");
+    }
+    builder =
+        builder.remote_dns(flag(args.remote_dns, args.no_remote_dns).unwrap_or(config.remote_dns));
+    let source_ips = if args.source_ips.is_empty() {
+        config.source_ips
+    } else {
+        args.source_ips
+    };
+    if source_ips.iter().filter(|ip| ip.is_ipv4()).count() > 1
+        || source_ips.iter().filter(|ip| ip.is_ipv6()).count() > 1
+    {
+        anyhow::bail!("at most one IPv4 and one IPv6 source address may be given");
+    }
+    for ip in source_ips {
+        builder = builder.source_ip(ip);
+    }
+    if let Some(ports) = or_parse(args.source_ports, config.source_ports.as_deref())? {
+        builder = builder.source_ports(ports);
+    }
+    if let Some(interface) = args.interface.or(config.interface) {
+        builder = builder.interface(interface);
+    }
+    if let Some(timeout) = args.bind_timeout.or(config.bind_timeout) {
+        builder = builder.bind_timeout(timeout);
+    }
+    let keepalive = Keepalive {
+        time: args.keepalive_time.or(config.keepalive.time),
+        interval: args.keepalive_interval.or(config.keepalive.interval),
+        retries: args.keepalive_retries.or(config.keepalive.retries),
+    };
+    if keepalive.time.is_some() || keepalive.interval.is_some() || keepalive.retries.is_some() {
+        builder = builder.keepalive(keepalive);
+    }
+    if let Some(timeout) = args.idle_timeout.or(config.idle_timeout) {
+        builder = builder.idle_timeout(timeout);
+    }
+    if let Some(timeout) = args.write_timeout.or(config.write_timeout) {
+        builder = builder.write_timeout(timeout);
+    }
+    if let Some(timeout) = args.handshake_timeout.or(config.handshake_timeout) {
+        builder = builder.handshake_timeout(timeout);
+    }
+    if let Some(limit) = args.max_session_duration.or(config.max_session_duration) {
+        builder = builder.max_session_duration(limit);
+    }
+    if let Some(size) = args.relay_buffer_size.or(config.relay_buffer_size) {
+        if size == 0 {
+            anyhow::bail!("relay buffer size must be positive");
+        }
+        builder = builder.relay_buffer_size(size);
+    }
+    if let Some(rate) = args.bandwidth_limit.or(config.bandwidth.limit) {
+        let Some(rate) = NonZeroU64::new(rate) else {
+            anyhow::bail!("bandwidth limit must be positive");
+        };
+        let scope = or_parse(args.bandwidth_scope, config.bandwidth.scope.as_deref())?;
+        builder = builder.bandwidth_limit(BandwidthLimit {
+            rate,
+            scope: scope.unwrap_or_default(),
+        });
+    }
+    if let Some(rate) = args
+        .global_bandwidth_limit
+        .or(config.bandwidth.global_limit)
+    {
+        let Some(rate) = NonZeroU64::new(rate) else {
+            anyhow::bail!("global bandwidth limit must be positive");
+        };
+        builder = builder.global_bandwidth_limit(rate);
+    }
+    if let Some(interval) = args.progress_interval.or(config.progress_interval) {
+        if interval.is_zero() {
+            anyhow::bail!("progress interval must be positive");
+        }
+        builder = builder.progress_interval(interval);
+    }
+    if let Some(timeout) = args.drain_timeout.or(config.drain_timeout) {
+        builder = builder.drain_timeout(timeout);
+    }
+    if let Some(backlog) = args.listen_backlog.or(config.listen_backlog) {
+        builder = builder.listen_backlog(backlog);
+    }
+    if let Some(max_connections) = args.max_connections.or(config.max_connections) {
+        builder = builder.max_connections(max_connections);
+    }
+    if let Some(policy) = or_parse(args.overload_policy, config.overload_policy.as_deref())? {
+        builder = builder.overload_policy(policy);
+    }
+    if let Some(soft_max_connections) = args.soft_max_connections.or(config.soft_max_connections) {
+        builder = builder.soft_max_connections(soft_max_connections);
+    }
+    if let Some(delay) = args.backpressure_delay.or(config.backpressure_delay) {
+        builder = builder.backpressure_delay(delay);
+    }
+    if let Some(timeout) = args.auth_timeout.or(config.auth.timeout) {
+        builder = builder.auth_timeout(timeout);
+    }
+    if let Some(policy) = or_parse(args.auth_policy, config.auth.policy.as_deref())? {
+        builder = builder.auth_policy(policy);
+    }
+    if !config.auth.users.is_empty() {
+        let users = config
+            .auth
+            .users
+            .into_iter()
+            .map(|(username, password)| (username.into_bytes(), password.into_bytes()))
+            .collect();
+        builder = builder.authenticator(Arc::new(StaticAuthenticator::new(users)));
+    }
+    if let Some(path) = args.credentials_file.or(config.auth.credentials_file) {
+        builder = builder.credentials_file(path);
+    }
+    if !args.allow_idents.is_empty() {
+        builder = builder.ident_authorizer(Arc::new(AllowedIdents::new(args.allow_idents)));
+    } else if !config.acl.allow_idents.is_empty() {
+        let idents = config.acl.allow_idents;
+        builder = builder.ident_authorizer(Arc::new(AllowedIdents::new(idents)));
+    }
+    if let Some(max_failures) = args.lockout_max_failures.or(config.lockout.max_failures) {
+        builder = builder.lockout(LockoutPolicy {
+            max_failures,
+            window: (args.lockout_window)
+                .or(config.lockout.window)
+                .unwrap_or(Duration::from_secs(5 * 60)),
+            cooldown: (args.lockout_cooldown)
+                .or(config.lockout.cooldown)
+                .unwrap_or(Duration::from_secs(15 * 60)),
+        });
+    }
+    if let Some(rate) = args.rate_limit.or(config.rate_limit.rate) {
+        if !rate.is_finite() || rate <= 0.0 {
+            anyhow::bail!("rate limit must be positive");
+        }
+        builder = builder.rate_limit(RateLimit {
+            rate,
+            burst: (args.rate_limit_burst)
+                .or(config.rate_limit.burst)
+                .unwrap_or(rate.ceil() as u32)
+                .max(1),
+        });
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = args.metrics_listen.or(config.metrics_listen) {
+        builder = builder.metrics_addr(addr);
+    }
+    #[cfg(feature = "admin")]
+    if let Some(addr) = args.admin_listen.or(config.admin_listen) {
+        builder = builder.admin_addr(addr);
+    }
+    #[cfg(feature = "admin")]
+    if let Some(token) = config.admin_token {
+        builder = builder.admin_token(token);
+    }
+    if let Some(addr) = args.health_listen.or(config.health_listen) {
+        builder = builder.health_addr(addr);
+    }
+    #[cfg(feature = "tls")]
+    match (
+        args.tls_cert.or(config.tls.cert),
+        args.tls_key.or(config.tls.key),
+    ) {
+        (Some(cert_path), Some(key_path)) => {
+            builder = builder.tls(musocks::Tls {
+                cert_path,
+                key_path,
+            });
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("a TLS certificate and key must be given together"),
+    }
+    builder.build().serve().await
+}
+
+// Builds the access rules, which SIGHUP rebuilds from the re-read config file. Command
+// line values take precedence over the file, as they do at startup.
+fn acl(args: &Args, config: &Config) -> anyhow::Result<Acl> {
+    let port_policy = if !args.allow_ports.is_empty() {
+        PortPolicy::Allow(args.allow_ports.clone())
+    } else if !args.deny_ports.is_empty() {
+        PortPolicy::Deny(args.deny_ports.clone())
+    } else if !config.acl.allow_ports.is_empty() && !config.acl.deny_ports.is_empty() {
+        anyhow::bail!("acl.allow_ports and acl.deny_ports are mutually exclusive");
+    } else if !config.acl.allow_ports.is_empty() {
+        PortPolicy::Allow(config.acl.allow_ports.clone())
+    } else if !config.acl.deny_ports.is_empty() {
+        PortPolicy::Deny(config.acl.deny_ports.clone())
+    } else {
+        PortPolicy::Any
     };
-    server.serve().await
+    let first_non_empty =
+        |arg: &[IpNet], file: &[IpNet]| if arg.is_empty() { file } else { arg }.to_vec();
+    let denied_domains = if args.deny_domains.is_empty() {
+        &config.acl.deny_domains
+    } else {
+        &args.deny_domains
+    };
+    Ok(Acl {
+        block_private_destinations: flag(
+            args.block_private_destinations,
+            args.no_block_private_destinations,
+        )
+        .unwrap_or(config.block_private_destinations),
+        port_policy,
+        denied_domains: DomainMatcher::new(denied_domains),
+        allowed_clients: first_non_empty(&args.allow_clients, &config.acl.allow),
+        stealth_errors: flag(args.stealth_errors, args.no_stealth_errors)
+            .unwrap_or(config.stealth_errors),
+        trusted_clients: first_non_empty(&args.trusted_clients, &config.acl.trusted),
+    })
+}
+
+// Returns the command line value if given, or else parses the config file value.
+fn or_parse<T: FromStr<Err = String>>(
+    arg: Option<T>,
+    file: Option<&str>,
+) -> anyhow::Result<Option<T>> {
+    match (arg, file) {
+        (Some(value), _) => Ok(Some(value)),
+        (None, Some(s)) => s.parse().map(Some).map_err(anyhow::Error::msg),
+        (None, None) => Ok(None),
+    }
+}
+
+// Returns the setting of a flag given on the command line as `--x` or `--no-x`, of which
+// the last one wins, or `None` if neither was given.
+fn flag(enable: bool, disable: bool) -> Option<bool> {
+    match (enable, disable) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+    // toml's error message points at the offending line and column.
+    toml::from_str(&content).map_err(|e| anyhow::anyhow!("invalid config {}:\n{e}", path.display()))
 }
 
-fn setup_logger() -> slog::Logger {
-    let decorator = slog_term::PlainSyncDecorator::new(std::io::stderr());
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    slog::Logger::root(drain, slog::o!())
+fn setup_logger(format: LogFormat, out: impl std::io::Write + Send + 'static) -> slog::Logger {
+    match format {
+        LogFormat::Text => {
+            let decorator = slog_term::PlainSyncDecorator::new(out);
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog::Logger::root(drain, slog::o!())
+        }
+        LogFormat::Json => {
+            // slog_json::Json is not Sync, so serialize writes through a mutex.
+            let drain = slog_json::Json::new(out).add_default_keys().build();
+            slog::Logger::root(std::sync::Mutex::new(drain).fuse(), slog::o!())
+        }
+    }
 }
@@ -0,0 +1,87 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+
+use crate::socks::{DomainMatcher, PortPolicy};
+
+// Acl holds the access rules that SIGHUP can replace while the server runs. Each check
+// reads the rules in effect at that moment, so sessions that already passed a check are
+// not affected by a reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    // Refuses to connect to loopback, private and link-local destinations, so that
+    // clients cannot reach internal services through the proxy. Domains sent to the
+    // upstream proxy are resolved there and cannot be checked.
+    pub block_private_destinations: bool,
+
+    // Destination ports clients may connect to.
+    pub port_policy: PortPolicy,
+
+    // Hostnames clients may not connect to.
+    pub denied_domains: DomainMatcher,
+
+    // Client networks allowed to use the proxy. Everyone is allowed when empty.
+    pub allowed_clients: Vec<IpNet>,
+
+    // Answers every failed request from clients outside `trusted_clients` with a generic
+    // failure after a random delay, so that scanners cannot tell a refused port from an
+    // unreachable host or a denied destination. Legitimate clients lose the detail too,
+    // which makes their failures harder to diagnose; trust the networks that need it.
+    pub stealth_errors: bool,
+    // Clients that get detailed failure replies with `stealth_errors`. Unix socket
+    // clients are always trusted.
+    pub trusted_clients: Vec<IpNet>,
+}
+
+// Builds the new rules on SIGHUP, typically by re-reading a configuration file. On an
+// error the current rules stay in effect.
+pub type AclLoader = Arc<dyn Fn() -> anyhow::Result<Acl> + Send + Sync>;
+
+impl Acl {
+    pub(crate) fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_clients.is_empty() || self.allowed_clients.iter().any(|net| net.contains(&ip))
+    }
+
+    // Whether failure replies to this client must hide their cause; see `stealth_errors`.
+    pub(crate) fn hides_errors_from(&self, client_ip: Option<IpAddr>) -> bool {
+        self.stealth_errors
+            && client_ip.is_some_and(|ip| !self.trusted_clients.iter().any(|n| n.contains(&ip)))
+    }
+
+    // Describes each rule that differs from `old`, for the reload log.
+    pub(crate) fn changes_from(&self, old: &Acl) -> Vec<String> {
+        let mut changes = Vec::new();
+        if self.block_private_destinations != old.block_private_destinations {
+            changes.push(format!(
+                "block_private_destinations={}",
+                self.block_private_destinations
+            ));
+        }
+        if self.port_policy != old.port_policy {
+            changes.push(format!("port_policy={:?}", self.port_policy));
+        }
+        if self.denied_domains != old.denied_domains {
+            changes.push(format!(
+                "denied_domains={} patterns",
+                self.denied_domains.len()
+            ));
+        }
+        if self.allowed_clients != old.allowed_clients {
+            changes.push(format!(
+                "allowed_clients={} networks",
+                self.allowed_clients.len()
+            ));
+        }
+        if self.stealth_errors != old.stealth_errors {
+            changes.push(format!("stealth_errors={}", self.stealth_errors));
+        }
+        if self.trusted_clients != old.trusted_clients {
+            changes.push(format!(
+                "trusted_clients={} networks",
+                self.trusted_clients.len()
+            ));
+        }
+        changes
+    }
+}
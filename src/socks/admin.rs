@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::socks::server::ConnId;
+use crate::socks::Server;
+
+// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest request head read; requests have no body.
+const MAX_REQUEST: u64 = 8192;
+
+// Serves the admin API, which needs `Authorization: Bearer <admin_token>`:
+//
+// - `GET /connections` lists the sessions as a JSON array, oldest first.
+// - `DELETE /connections/{id}` closes a session, which is logged and counted as cancelled.
+pub(crate) async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                slog::error!(server.logger, "failed to accept admin request"; "err" => %e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader).take(MAX_REQUEST);
+            let mut head = Vec::new();
+            let read = async {
+                // The head ends with an empty line.
+                while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+                    if reader.read_until(b'\n', &mut head).await? == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                Ok::<_, std::io::Error>(())
+            };
+            if !matches!(
+                tokio::time::timeout(REQUEST_TIMEOUT, read).await,
+                Ok(Ok(()))
+            ) {
+                return;
+            }
+            let head = String::from_utf8_lossy(&head);
+            let response = respond(&head, &server, &peer_addr.to_string());
+            let _ = writer.write_all(response.as_bytes()).await;
+            let _ = writer.shutdown().await;
+        });
+    }
+}
+
+fn respond(head: &str, server: &Server, peer_addr: &str) -> String {
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next().unwrap_or_default());
+    let token = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "));
+    let expected = server.admin_token.as_deref().unwrap_or_default();
+    let authorized =
+        token.is_some_and(|token| bool::from(token.as_bytes().ct_eq(expected.as_bytes())));
+    if !authorized {
+        slog::warn!(server.logger, "admin request unauthorized"; "admin_client" => peer_addr);
+        return response("401 Unauthorized", "WWW-Authenticate: Bearer\r\n", "");
+    }
+
+    match (method, path.strip_prefix("/connections")) {
+        (Some("GET"), Some("")) => {
+            let sessions: Vec<_> = (server.sessions().snapshot())
+                .into_iter()
+                .map(|(id, info)| {
+                    let totals = info.traffic.as_ref().map(|traffic| traffic.totals());
+                    let id = match id {
+                        ConnId::Counter(n) => json!(n),
+                        ConnId::Uuid(uuid) => json!(uuid.to_string()),
+                    };
+                    json!({
+                        "id": id,
+                        "client_addr": info.client_addr.to_string(),
+                        "protocol": info.protocol,
+                        "user": info.user,
+                        "destination": info.destination.map(|d| d.to_string()),
+                        "uploaded_bytes": totals.map(|(uploaded, _)| uploaded),
+                        "downloaded_bytes": totals.map(|(_, downloaded)| downloaded),
+                        "age_secs": info.started_at.elapsed().as_secs_f64(),
+                    })
+                })
+                .collect();
+            let body = serde_json::Value::Array(sessions).to_string();
+            response("200 OK", "Content-Type: application/json\r\n", &body)
+        }
+        (Some("DELETE"), Some(id)) if id.starts_with('/') => {
+            let Ok(id) = id[1..].parse::<ConnId>() else {
+                return response("404 Not Found", "", "");
+            };
+            if !server.sessions().cancel(id) {
+                return response("404 Not Found", "", "");
+            }
+            slog::info!(server.logger, "cancelling session";
+                "id" => id, "admin_client" => peer_addr);
+            response("204 No Content", "", "")
+        }
+        (_, Some("")) => response("405 Method Not Allowed", "Allow: GET\r\n", ""),
+        (_, Some(id)) if id.starts_with('/') => {
+            response("405 Method Not Allowed", "Allow: DELETE\r\n", "")
+        }
+        _ => response("404 Not Found", "", ""),
+    }
+}
+
+fn response(status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.0 {status}\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use subtle::{ConstantTimeEq, ConstantTimeLess};
+
+pub enum Auth<'a> {
+    None,
+    UsernamePassword {
+        username: &'a [u8],
+        password: &'a [u8],
+    },
+}
+
+pub enum AuthResult {
+    Accept,
+    Deny,
+}
+
+// AuthPolicy decides which method is chosen when a client offers both
+// username/password and no authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthPolicy {
+    // Only username/password is accepted.
+    RequireAuth,
+    // Username/password is chosen whenever the client offers it.
+    #[default]
+    PreferAuth,
+    // No authentication is chosen if the authenticator permits anonymous access.
+    PreferAnonymous,
+}
+
+impl FromStr for AuthPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "require-auth" => Ok(AuthPolicy::RequireAuth),
+            "prefer-auth" => Ok(AuthPolicy::PreferAuth),
+            "prefer-anonymous" => Ok(AuthPolicy::PreferAnonymous),
+            _ => Err(format!(
+                "unknown auth policy `{s}` (expected require-auth, prefer-auth or prefer-anonymous)"
+            )),
+        }
+    }
+}
+
+// Authenticator decides whether a SOCKS5 client is allowed to use the proxy.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, auth: Auth<'_>) -> AuthResult;
+}
+
+// AllowAnonymous accepts clients without credentials and rejects every login.
+pub struct AllowAnonymous;
+
+#[async_trait]
+impl Authenticator for AllowAnonymous {
+    async fn authenticate(&self, auth: Auth<'_>) -> AuthResult {
+        match auth {
+            Auth::None => AuthResult::Accept,
+            Auth::UsernamePassword { .. } => AuthResult::Deny,
+        }
+    }
+}
+
+// IdentAuthorizer decides whether a SOCKS4 request may proceed, given the ident the client
+// sent (`None` if it sent an empty one) and its IP address if known. The ident is only what
+// the client claims, so it is as trustworthy as the network it comes from.
+#[async_trait]
+pub trait IdentAuthorizer: Send + Sync {
+    async fn authorize(&self, ident: Option<&str>, client_ip: Option<IpAddr>) -> AuthResult;
+}
+
+// AllowedIdents accepts SOCKS4 requests whose ident is in a fixed set.
+pub struct AllowedIdents {
+    idents: HashSet<String>,
+}
+
+impl AllowedIdents {
+    pub fn new(idents: impl IntoIterator<Item = String>) -> Self {
+        AllowedIdents {
+            idents: idents.into_iter().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl IdentAuthorizer for AllowedIdents {
+    async fn authorize(&self, ident: Option<&str>, _client_ip: Option<IpAddr>) -> AuthResult {
+        match ident {
+            Some(ident) if self.idents.contains(ident) => AuthResult::Accept,
+            _ => AuthResult::Deny,
+        }
+    }
+}
+
+// StaticAuthenticator checks username/password logins against a fixed set of users.
+// Anonymous clients are rejected.
+pub struct StaticAuthenticator {
+    users: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(users: HashMap<Vec<u8>, Vec<u8>>) -> Self {
+        StaticAuthenticator { users }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, auth: Auth<'_>) -> AuthResult {
+        let Auth::UsernamePassword { username, password } = auth else {
+            return AuthResult::Deny;
+        };
+        // Unknown users and wrong passwords are indistinguishable to the client.
+        match self.users.get(username) {
+            Some(expected) => verify_password(expected, password),
+            None => AuthResult::Deny,
+        }
+    }
+}
+
+// Checks a plaintext password without leaking timing information about the expected one.
+// The running time depends only on the length of `expected`, never on where `given`
+// differs from it. Backends storing password hashes should use the hashing library's
+// own verify function instead, which is constant-time for the same reason.
+pub fn verify_password(expected: &[u8], given: &[u8]) -> AuthResult {
+    let mut equal = (expected.len() as u64).ct_eq(&(given.len() as u64));
+    for (i, e) in expected.iter().enumerate() {
+        let in_range = (i as u64).ct_lt(&(given.len() as u64));
+        let g = given.get(i).copied().unwrap_or(0);
+        equal &= !in_range | e.ct_eq(&g);
+    }
+    if bool::from(equal) {
+        AuthResult::Accept
+    } else {
+        AuthResult::Deny
+    }
+}
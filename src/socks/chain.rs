@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::socks::*;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+// UpstreamProxy is a SOCKS5 proxy that connections are forwarded through instead of
+// connecting to destinations directly.
+#[derive(Debug, Clone)]
+pub struct UpstreamProxy {
+    pub addr: SocketAddr,
+    // Username and password offered to the proxy. Only "no authentication" is offered
+    // when `None`.
+    pub credentials: Option<(String, String)>,
+}
+
+// Connects to the proxy and asks it to CONNECT to the destination. Returns the tunneled
+// stream, which behaves like a direct connection to the destination.
+pub(crate) async fn connect(
+    proxy: &UpstreamProxy,
+    addr: &Address,
+    port: u16,
+    server: &Server,
+) -> Result<TcpStream> {
+    let mut stream = connect_tcp(proxy.addr, server).await?;
+    negotiate_auth(&mut stream, proxy).await?;
+    write_connect(&mut stream, addr, port).await?;
+    read_reply(&mut stream).await?;
+    Ok(stream)
+}
+
+async fn negotiate_auth(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    proxy: &UpstreamProxy,
+) -> Result<()> {
+    match &proxy.credentials {
+        Some(_) => {
+            stream
+                .write_all(&[SOCKS5, 2, METHOD_NONE, METHOD_USERNAME_PASSWORD])
+                .await?
+        }
+        None => stream.write_all(&[SOCKS5, 1, METHOD_NONE]).await?,
+    }
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS5 {
+        return Err(Error::UpstreamProxyError("upstream proxy is not SOCKS5"));
+    }
+    match (choice[1], &proxy.credentials) {
+        (METHOD_NONE, _) => Ok(()),
+        (METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+            // RFC 1929 username/password subnegotiation.
+            let (Ok(username_len), Ok(password_len)) =
+                (u8::try_from(username.len()), u8::try_from(password.len()))
+            else {
+                return Err(Error::UpstreamProxyError(
+                    "upstream proxy username or password is too long",
+                ));
+            };
+            let mut buf = vec![0x01, username_len];
+            buf.extend_from_slice(username.as_bytes());
+            buf.push(password_len);
+            buf.extend_from_slice(password.as_bytes());
+            stream.write_all(&buf).await?;
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(Error::UpstreamProxyError(
+                    "upstream proxy rejected the credentials",
+                ));
+            }
+            Ok(())
+        }
+        (METHOD_NO_ACCEPTABLE, _) => Err(Error::UpstreamProxyError(
+            "upstream proxy accepted no offered auth method",
+        )),
+        _ => Err(Error::UpstreamProxyError(
+            "upstream proxy chose an auth method that was not offered",
+        )),
+    }
+}
+
+async fn write_connect(
+    stream: &mut (impl AsyncWrite + Unpin),
+    addr: &Address,
+    port: u16,
+) -> Result<()> {
+    let mut buf: ByteBuf = smallvec::smallvec![SOCKS5, COMMAND_CONNECT, 0x00];
+    match addr {
+        Address::IPv4(ip) => {
+            buf.push(0x01);
+            buf.extend_from_slice(ip);
+        }
+        Address::IPv6(ip) => {
+            buf.push(0x04);
+            buf.extend_from_slice(ip);
+        }
+        Address::Domain(domain) => {
+            // Domains are validated to at most 253 bytes (plus a trailing dot) at parse time.
+            buf.push(0x03);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain);
+        }
+    }
+    buf.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+// Reads the reply to CONNECT. The bound address is read and discarded.
+async fn read_reply(stream: &mut (impl AsyncRead + Unpin)) -> Result<()> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS5 {
+        return Err(Error::UpstreamProxyError(
+            "upstream proxy reply is not SOCKS5",
+        ));
+    }
+    let addr_len = match header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        _ => {
+            return Err(Error::UpstreamProxyError(
+                "upstream proxy replied with an unknown address type",
+            ))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    match header[1] {
+        0x00 => Ok(()),
+        reply => Err(Error::UpstreamProxyRefused(reply)),
+    }
+}
@@ -0,0 +1,143 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+
+use crate::socks::*;
+
+// Connector makes the upstream connections of CONNECT requests. The server checks the
+// domain denylist and applies the connect timeout around it.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    async fn connect(&self, addr: &Address, port: u16, server: &Server) -> Result<TcpStream>;
+}
+
+// DirectConnector resolves the destination and connects to it, or goes through the
+// server's upstream proxy if one is configured. It honors the server's address family,
+// source address, interface, keepalive and private destination settings.
+pub struct DirectConnector;
+
+#[async_trait]
+impl Connector for DirectConnector {
+    async fn connect(&self, addr: &Address, port: u16, server: &Server) -> Result<TcpStream> {
+        if let Some(proxy) = &server.upstream_proxy {
+            return connect_via_proxy(addr, port, proxy, server).await;
+        }
+        if server.remote_dns && matches!(addr, Address::Domain(_)) {
+            let err = io::Error::other("domains are only resolved by the upstream proxy");
+            return Err(Error::ResolveFailed(addr.to_string(), err));
+        }
+        let mut addrs = resolve_address(addr, port, server.resolver.as_ref())
+            .await
+            .map_err(|e| Error::ResolveFailed(addr.to_string(), e))?;
+        server.address_family.apply(&mut addrs)?;
+        if server.acl().block_private_destinations {
+            let first = addrs[0];
+            addrs.retain(|a| !is_private_destination(a.ip()));
+            if addrs.is_empty() {
+                return Err(Error::DestinationBlocked(first));
+            }
+        }
+        happy_eyeballs(addrs, server).await
+    }
+}
+
+// How long an attempt gets before the next address is tried in parallel (RFC 8305).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type Attempt<'a> = Pin<Box<dyn Future<Output = Result<TcpStream>> + Send + 'a>>;
+
+// Races connections to `addrs` as in Happy Eyeballs (RFC 8305): attempts start one
+// `CONNECTION_ATTEMPT_DELAY` apart, or as soon as the previous one fails, alternating
+// between IPv6 and IPv4, and the first to succeed wins. A broken IPv6 path thus delays the
+// connection by the attempt delay instead of a full connect timeout.
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, server: &Server) -> Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            let Some(addr) = pending.next() else {
+                return Err(last_err.expect("resolve_address returns at least one address"));
+            };
+            attempts.push(Box::pin(connect_tcp(addr, server)));
+        }
+        let has_pending = pending.len() > 0;
+        tokio::select! {
+            result = first_completed(&mut attempts) => match result {
+                Ok(stream) => return Ok(stream),
+                // A bind failure would repeat for every address of the family.
+                Err(e @ (Error::SourceBindFailed(..) | Error::InterfaceBindFailed(..))) => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    last_err = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(Box::pin(connect_tcp(addr, server)));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if has_pending => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(Box::pin(connect_tcp(addr, server)));
+                }
+            }
+        }
+    }
+}
+
+// Completes with the result of whichever attempt finishes first, removing it.
+async fn first_completed(attempts: &mut Vec<Attempt<'_>>) -> Result<TcpStream> {
+    std::future::poll_fn(|cx| {
+        for i in 0..attempts.len() {
+            if let Poll::Ready(result) = attempts[i].as_mut().poll(cx) {
+                drop(attempts.swap_remove(i));
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+// Reorders addresses to alternate between families, starting with the family of the
+// resolver's first choice and otherwise keeping its order.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let prefer_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// Connects through the upstream proxy, which resolves domains itself. Only literal
+// addresses can be checked against `block_private_destinations` here.
+async fn connect_via_proxy(
+    addr: &Address,
+    port: u16,
+    proxy: &UpstreamProxy,
+    server: &Server,
+) -> Result<TcpStream> {
+    if server.acl().block_private_destinations && !matches!(addr, Address::Domain(_)) {
+        let dst = resolve_address(addr, port, server.resolver.as_ref()).await?[0];
+        if is_private_destination(dst.ip()) {
+            return Err(Error::DestinationBlocked(dst));
+        }
+    }
+    chain::connect(proxy, addr, port, server).await
+}
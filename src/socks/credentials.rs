@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use argon2::password_hash::phc::PasswordHash;
+use argon2::{Argon2, CustomizedPasswordHasher, PasswordVerifier};
+use async_trait::async_trait;
+use slog::{info, warn};
+
+use crate::socks::*;
+
+struct Credentials {
+    users: HashMap<Vec<u8>, String>,
+    // Verified against for unknown usernames, so that they take as long to deny as wrong
+    // passwords and response times do not tell which usernames exist.
+    dummy_hash: Option<String>,
+}
+
+// FileAuthenticator checks username/password logins against a file of
+// `username:password_hash` lines, where the hash is either argon2 (PHC string) or bcrypt.
+pub struct FileAuthenticator {
+    path: PathBuf,
+    logger: slog::Logger,
+    users: RwLock<Arc<Credentials>>,
+}
+
+impl FileAuthenticator {
+    pub fn load(path: &Path, logger: &slog::Logger) -> io::Result<Self> {
+        let users = read_credentials(path, logger)?;
+        info!(logger, "credentials loaded";
+            "path" => %path.display(), "users" => users.users.len());
+        Ok(FileAuthenticator {
+            path: path.to_path_buf(),
+            logger: logger.clone(),
+            users: RwLock::new(Arc::new(users)),
+        })
+    }
+
+    // Re-reads the file and returns the number of users. The current credentials are
+    // kept if the file cannot be read. Logins in progress finish with the old ones.
+    pub fn reload(&self) -> io::Result<usize> {
+        let users = read_credentials(&self.path, &self.logger)?;
+        let n_users = users.users.len();
+        *self.users.write().unwrap() = Arc::new(users);
+        Ok(n_users)
+    }
+}
+
+#[async_trait]
+impl Authenticator for FileAuthenticator {
+    async fn authenticate(&self, auth: Auth<'_>) -> AuthResult {
+        let Auth::UsernamePassword { username, password } = auth else {
+            return AuthResult::Deny;
+        };
+        let credentials = self.users.read().unwrap().clone();
+        let (hash, known) = match credentials.users.get(username) {
+            Some(hash) => (hash.clone(), true),
+            None => match &credentials.dummy_hash {
+                Some(hash) => (hash.clone(), false),
+                None => return AuthResult::Deny,
+            },
+        };
+        // Hash verification is CPU-heavy, so keep it off the async workers.
+        // Both argon2 and bcrypt compare the digests in constant time.
+        let password = password.to_vec();
+        let verified = tokio::task::spawn_blocking(move || verify_hash(&password, &hash))
+            .await
+            .unwrap_or(false);
+        if verified && known {
+            AuthResult::Accept
+        } else {
+            AuthResult::Deny
+        }
+    }
+}
+
+fn read_credentials(path: &Path, logger: &slog::Logger) -> io::Result<Credentials> {
+    let content = std::fs::read_to_string(path)?;
+    let mut users = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((username, hash)) if !username.is_empty() && is_valid_hash(hash) => {
+                users.insert(username.as_bytes().to_vec(), hash.to_string());
+            }
+            _ => {
+                warn!(logger, "skipping malformed credentials line";
+                    "path" => %path.display(), "line" => i + 1);
+            }
+        }
+    }
+    // Hashes usually share one algorithm and cost, so any one stands for them all.
+    let dummy_hash = users.values().next().and_then(|hash| dummy_hash_like(hash));
+    Ok(Credentials { users, dummy_hash })
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok()
+    } else {
+        hash.parse::<bcrypt::HashParts>().is_ok()
+    }
+}
+
+// Returns a hash of the empty password with the algorithm and cost of `hash`, so that
+// verifying against it takes as long.
+fn dummy_hash_like(hash: &str) -> Option<String> {
+    if hash.starts_with("$argon2") {
+        let hash = PasswordHash::new(hash).ok()?;
+        let params = argon2::Params::try_from(&hash).ok()?;
+        let dummy = Argon2::default()
+            .hash_password_customized(
+                b"",
+                &[0; 16],
+                Some(hash.algorithm.as_str()),
+                hash.version,
+                params,
+            )
+            .ok()?;
+        Some(dummy.to_string())
+    } else {
+        let cost = hash.parse::<bcrypt::HashParts>().ok()?.get_cost();
+        let dummy = bcrypt::hash_with_salt(b"", cost, [0; 16]).ok()?;
+        Some(dummy.to_string())
+    }
+}
+
+fn verify_hash(password: &[u8], hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return false;
+        };
+        Argon2::default().verify_password(password, &hash).is_ok()
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
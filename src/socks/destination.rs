@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+
+// PortPolicy restricts the destination ports clients may connect to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PortPolicy {
+    #[default]
+    Any,
+    // Only these ports are allowed.
+    Allow(Vec<u16>),
+    // All ports except these are allowed.
+    Deny(Vec<u16>),
+}
+
+impl PortPolicy {
+    pub fn is_allowed(&self, port: u16) -> bool {
+        match self {
+            PortPolicy::Any => true,
+            PortPolicy::Allow(ports) => ports.contains(&port),
+            PortPolicy::Deny(ports) => !ports.contains(&port),
+        }
+    }
+}
+
+// DomainMatcher matches requested hostnames against exact names and `*.suffix` patterns.
+// A wildcard pattern matches subdomains only, not the suffix itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainMatcher {
+    exact: HashSet<String>,
+    // Suffixes including the leading dot, e.g. ".doubleclick.net".
+    suffixes: Vec<String>,
+}
+
+impl DomainMatcher {
+    pub fn new<I, S>(patterns: I) -> DomainMatcher
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut matcher = DomainMatcher::default();
+        for pattern in patterns {
+            let pattern = normalize(pattern.as_ref());
+            match pattern.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') => matcher.suffixes.push(suffix.to_owned()),
+                _ => {
+                    matcher.exact.insert(pattern);
+                }
+            }
+        }
+        matcher
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    // Number of patterns.
+    pub fn len(&self) -> usize {
+        self.exact.len() + self.suffixes.len()
+    }
+
+    pub fn matches(&self, domain: &[u8]) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let domain = normalize(&String::from_utf8_lossy(domain));
+        self.exact.contains(&domain) || self.suffixes.iter().any(|s| domain.ends_with(s.as_str()))
+    }
+}
+
+// Lowercases the name and strips a trailing dot, so that "Example.COM." equals "example.com".
+fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use tokio::sync::OnceCell;
+
+use crate::socks::{Metrics, Resolver};
+
+// DnsCache configures caching of resolved domain names.
+#[derive(Debug, Clone, Copy)]
+pub struct DnsCache {
+    // Most names kept at once; the least recently used is evicted beyond this.
+    pub capacity: usize,
+    // How long a successful lookup is reused.
+    pub ttl: Duration,
+    // How long a failed or empty lookup is reused.
+    pub negative_ttl: Duration,
+}
+
+// The result of a lookup in a form that can be handed to every caller waiting for it.
+type Lookup = std::result::Result<Vec<IpAddr>, (io::ErrorKind, String)>;
+
+// A lowercased name, with the client subnet the answer was tailored to if any.
+type Key = (String, Option<IpNet>);
+
+struct Entry {
+    lookup: Lookup,
+    expires_at: Instant,
+    // Value of `State::clock` when the entry was last read, for LRU eviction.
+    used_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, Entry>,
+    // Lookups in progress. Callers asking for the same name share one cell, so only one
+    // query goes out per name however many connections want it.
+    in_flight: HashMap<Key, Arc<OnceCell<Lookup>>>,
+    clock: u64,
+}
+
+// CachingResolver wraps another resolver with an LRU cache of its results.
+pub(crate) struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    config: DnsCache,
+    metrics: Arc<Metrics>,
+    state: Mutex<State>,
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>, config: DnsCache, metrics: Arc<Metrics>) -> Self {
+        CachingResolver {
+            inner,
+            config,
+            metrics,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn insert(&self, state: &mut State, key: Key, lookup: Lookup) {
+        let now = Instant::now();
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.config.capacity {
+            state.entries.retain(|_, entry| entry.expires_at > now);
+            if state.entries.len() >= self.config.capacity {
+                // A linear scan keeps the table simple; it only runs when the cache is full.
+                let oldest = state
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    state.entries.remove(&oldest);
+                }
+            }
+        }
+        let ttl = match &lookup {
+            Ok(ips) if !ips.is_empty() => self.config.ttl,
+            _ => self.config.negative_ttl,
+        };
+        let used_at = state.clock;
+        state.entries.insert(
+            key,
+            Entry {
+                lookup,
+                expires_at: now + ttl,
+                used_at,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl Resolver for CachingResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        let name = domain.trim_end_matches('.').to_ascii_lowercase();
+        let key = (name, self.inner.client_subnet());
+        let cell = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(&key) {
+                if entry.expires_at > Instant::now() {
+                    entry.used_at = clock;
+                    self.metrics.record_dns_cache_hit();
+                    return into_result(entry.lookup.clone());
+                }
+            }
+            self.metrics.record_dns_cache_miss();
+            state.in_flight.entry(key.clone()).or_default().clone()
+        };
+
+        let lookup = cell
+            .get_or_init(|| async {
+                self.inner
+                    .resolve(domain)
+                    .await
+                    .map_err(|e| (e.kind(), e.to_string()))
+            })
+            .await
+            .clone();
+
+        // Whichever waiter gets here first moves the result into the cache.
+        let mut state = self.state.lock().unwrap();
+        if state
+            .in_flight
+            .get(&key)
+            .is_some_and(|c| Arc::ptr_eq(c, &cell))
+        {
+            state.in_flight.remove(&key);
+            self.insert(&mut state, key, lookup.clone());
+        }
+        into_result(lookup)
+    }
+
+    fn client_subnet(&self) -> Option<IpNet> {
+        self.inner.client_subnet()
+    }
+}
+
+fn into_result(lookup: Lookup) -> io::Result<Vec<IpAddr>> {
+    lookup.map_err(|(kind, message)| io::Error::new(kind, message))
+}
@@ -0,0 +1,204 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, ServerName};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsConnector;
+
+use crate::socks::nameserver::{encode_query, merge_families, parse_response, TYPE_A, TYPE_AAAA};
+use crate::socks::Resolver;
+
+// Upper bound on one query, including the TCP and TLS handshakes.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Largest HTTP response read; DNS messages cannot exceed 64 KiB.
+const MAX_RESPONSE: u64 = 70 * 1024;
+// Where the CA bundle lives on common Linux distributions and the BSDs.
+const SYSTEM_CA_FILES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+// DohEndpoint is the URL of a DNS-over-HTTPS server, such as
+// `https://dns.quad9.net/dns-query`. The path defaults to `/dns-query`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DohEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl FromStr for DohEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid DoH endpoint `{s}`: {reason}");
+        let rest = s
+            .strip_prefix("https://")
+            .ok_or_else(|| err("must start with https://"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/dns-query"),
+        };
+        let (host, port) = if let Ok(addr) = authority.parse::<SocketAddr>() {
+            (addr.ip().to_string(), addr.port())
+        } else if let Some((host, port)) = authority
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.contains(':'))
+        {
+            (host.to_owned(), port.parse().map_err(|_| err("bad port"))?)
+        } else {
+            let host = authority.trim_start_matches('[').trim_end_matches(']');
+            (host.to_owned(), 443)
+        };
+        if host.is_empty() {
+            return Err(err("no host"));
+        }
+        Ok(DohEndpoint {
+            host,
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Display for DohEndpoint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => write!(f, "https://[{ip}]:{}{}", self.port, self.path),
+            _ => write!(f, "https://{}:{}{}", self.host, self.port, self.path),
+        }
+    }
+}
+
+// DohResolver looks domains up over DNS-over-HTTPS (RFC 8484), so that networks on the
+// way can neither see nor tamper with the lookups. Each query opens its own connection;
+// enable the DNS cache to avoid paying for that on every session.
+pub struct DohResolver {
+    endpoint: DohEndpoint,
+    server_name: ServerName<'static>,
+    bootstrap: Option<IpAddr>,
+    connector: TlsConnector,
+}
+
+impl DohResolver {
+    // Queries `endpoint`, verifying its certificate against the CAs in `ca_file`, or in
+    // the system's CA bundle when `None`.
+    pub fn new(endpoint: DohEndpoint, ca_file: Option<&Path>) -> io::Result<Self> {
+        let server_name = ServerName::try_from(endpoint.host.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ca_file = match ca_file {
+            Some(path) => path,
+            None => SYSTEM_CA_FILES
+                .iter()
+                .map(Path::new)
+                .find(|path| path.exists())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no system CA bundle"))?,
+        };
+        let certs = CertificateDer::pem_file_iter(ca_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| io::Error::other(format!("{}: {e}", ca_file.display())))?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_parsable_certificates(certs);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(DohResolver {
+            endpoint,
+            server_name,
+            bootstrap: None,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    // Connects to `ip` instead of resolving the endpoint's host name, which would
+    // otherwise go through the very system resolver DoH is meant to replace. The
+    // certificate is still checked against the host name.
+    pub fn bootstrap(mut self, ip: IpAddr) -> Self {
+        self.bootstrap = Some(ip);
+        self
+    }
+
+    async fn query(&self, domain: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        // RFC 8484 asks for ID 0 so that HTTP caches can share answers.
+        let request = encode_query(0, domain, qtype, None)?;
+        let response = tokio::time::timeout(QUERY_TIMEOUT, self.exchange(&request))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DoH server timed out"))??;
+        parse_response(&response, qtype)
+    }
+
+    async fn exchange(&self, request: &[u8]) -> io::Result<Vec<u8>> {
+        let endpoint = &self.endpoint;
+        let stream = match self.bootstrap {
+            Some(ip) => TcpStream::connect((ip, endpoint.port)).await?,
+            None => TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?,
+        };
+        let mut stream = (self.connector)
+            .connect(self.server_name.clone(), stream)
+            .await?;
+
+        // HTTP/1.0, so that the body is neither chunked nor followed by another response.
+        let header = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+             Accept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+            endpoint.path,
+            endpoint.host,
+            request.len()
+        );
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(request).await?;
+        stream.flush().await?;
+        let mut response = Vec::new();
+        match (&mut stream)
+            .take(MAX_RESPONSE)
+            .read_to_end(&mut response)
+            .await
+        {
+            Ok(_) => {}
+            // Many servers close without a TLS close_notify; Content-Length catches truncation.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(e) => return Err(e),
+        }
+
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response");
+        let end = (response.windows(4))
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(malformed)?;
+        let head = String::from_utf8_lossy(&response[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split(' ').nth(1) != Some("200") {
+            return Err(io::Error::other(format!("DoH server answered `{status}`")));
+        }
+        let content_length = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+        if content_length != Some(response.len() - end - 4) {
+            return Err(malformed());
+        }
+        Ok(response.split_off(end + 4))
+    }
+}
+
+#[async_trait]
+impl Resolver for DohResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        let (v6, v4) = tokio::join!(self.query(domain, TYPE_AAAA), self.query(domain, TYPE_A));
+        merge_families(v6, v4)
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::socks::Server;
+
+// How long a probe has to send an HTTP request before it is treated as a plain TCP check.
+const REQUEST_WAIT: Duration = Duration::from_millis(250);
+
+// Answers liveness probes. A client that sends `GET /healthz` within `REQUEST_WAIT` gets
+// an HTTP 200, or a 503 while the server is draining; any other client gets `OK\n` or
+// `DRAINING\n`, so that plain TCP checks with a response match work too.
+pub(crate) async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                slog::error!(server.logger, "failed to accept health check"; "err" => %e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            // Only the request line matters; cap it so a client cannot make us buffer forever.
+            let mut reader = BufReader::new(reader).take(8192);
+            let mut request_line = String::new();
+            let read = tokio::time::timeout(REQUEST_WAIT, reader.read_line(&mut request_line));
+            let _ = read.await;
+            let draining = server.is_draining();
+            let response = if request_line.starts_with("GET /healthz ") && draining {
+                "HTTP/1.0 503 Service Unavailable\r\nContent-Length: 9\r\n\r\nDRAINING\n"
+            } else if request_line.starts_with("GET /healthz ") {
+                "HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\nOK\n"
+            } else if request_line.starts_with("GET ") {
+                "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+            } else if draining {
+                "DRAINING\n"
+            } else {
+                "OK\n"
+            };
+            let _ = writer.write_all(response.as_bytes()).await;
+            let _ = writer.shutdown().await;
+        });
+    }
+}
@@ -0,0 +1,191 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use base64::Engine;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks::*;
+
+// Upper bound on the request line plus headers, so that a client cannot make us buffer
+// without bound.
+const MAX_HEADER_LEN: usize = 8192;
+
+// Handles an HTTP CONNECT request. `preamble` holds the first bytes of the request line,
+// which were consumed to tell HTTP apart from SOCKS.
+pub async fn handshake(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    preamble: [u8; 2],
+    server: &Server,
+) -> Result<(Request, Upstream)> {
+    do_handshake(reader, writer, preamble, None, server, &mut None).await
+}
+
+// Like `handshake`, but also reports the requested destination in `dst`. Failure replies
+// to `client_ip` are generic if the server hides errors from it.
+pub(crate) async fn do_handshake(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    preamble: [u8; 2],
+    client_ip: Option<IpAddr>,
+    server: &Server,
+    dst: &mut Option<Destination>,
+) -> Result<(Request, Upstream)> {
+    let head = match tokio::time::timeout(server.auth_timeout, read_head(reader, preamble)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::AuthTimeout),
+    };
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        write_response(writer, "400 Bad Request", "").await?;
+        return Err(Error::ProtocolError("malformed HTTP request line"));
+    };
+    if method != "CONNECT" {
+        write_response(writer, "405 Method Not Allowed", "Allow: CONNECT\r\n").await?;
+        return Err(Error::ProtocolError("HTTP method not allowed"));
+    }
+    let Some((address, port)) = parse_target(target) else {
+        write_response(writer, "400 Bad Request", "").await?;
+        return Err(Error::ProtocolError("malformed CONNECT target"));
+    };
+    *dst = Some(Destination::new(&address, port));
+
+    let credentials = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| parse_basic(value.trim()));
+    let user = match authenticate(credentials, server).await {
+        Ok(user) => user,
+        Err(e) => {
+            let headers = "Proxy-Authenticate: Basic realm=\"musocks\"\r\n";
+            write_response(writer, "407 Proxy Authentication Required", headers).await?;
+            return Err(e);
+        }
+    };
+
+    let mut request = Request {
+        command: COMMAND_CONNECT,
+        address,
+        port,
+        user,
+        upstream_addr: None,
+        ident: None,
+    };
+    if !server.acl().port_policy.is_allowed(request.port) {
+        let status = failure_reply("403 Forbidden", "502 Bad Gateway", server, client_ip).await;
+        write_response(writer, status, "").await?;
+        return Err(Error::PortNotAllowed(
+            request.address.to_string(),
+            request.port,
+        ));
+    }
+    let (upstream, upstream_addr) =
+        match connect_to_upstream(&request.address, request.port, server).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                let status = match &e {
+                    Error::UpstreamConnect(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        "504 Gateway Timeout"
+                    }
+                    Error::DestinationBlocked(_) | Error::DomainDenied(_) => "403 Forbidden",
+                    _ => "502 Bad Gateway",
+                };
+                let status = failure_reply(status, "502 Bad Gateway", server, client_ip).await;
+                write_response(writer, status, "").await?;
+                return Err(e);
+            }
+        };
+    request.upstream_addr = upstream_addr;
+    write_response(writer, "200 Connection Established", "").await?;
+    Ok((request, Upstream::Tcp(upstream)))
+}
+
+// Reads the request line and headers up to the blank line that ends them.
+async fn read_head(reader: &mut (impl AsyncBufRead + Unpin), preamble: [u8; 2]) -> Result<String> {
+    let mut buf = preamble.to_vec();
+    loop {
+        let limit = (MAX_HEADER_LEN + 1).saturating_sub(buf.len()) as u64;
+        let n = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut buf)
+            .await?;
+        if buf.ends_with(b"\r\n\r\n") || buf.ends_with(b"\n\n") {
+            break;
+        }
+        if buf.len() > MAX_HEADER_LEN {
+            return Err(Error::ProtocolError("HTTP request header is too long"));
+        }
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    String::from_utf8(buf).map_err(|_| Error::ProtocolError("HTTP request is not utf-8"))
+}
+
+// Parses `host:port`, where host is an IPv4 address, a bracketed IPv6 address or a
+// domain name.
+fn parse_target(target: &str) -> Option<(Address, u16)> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        let address = match addr.ip() {
+            IpAddr::V4(ip) => Address::IPv4(ip.octets()),
+            IpAddr::V6(ip) => Address::IPv6(ip.octets()),
+        };
+        return Some((address, addr.port()));
+    }
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    validate_domain(host.as_bytes()).ok()?;
+    Some((Address::Domain(host.as_bytes().into()), port))
+}
+
+// Decodes the credentials of a `Basic` Proxy-Authorization value.
+fn parse_basic(value: &str) -> Option<(ByteBuf, ByteBuf)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    Some((decoded[..colon].into(), decoded[colon + 1..].into()))
+}
+
+// Applies the same rules as SOCKS5 method selection: credentials are checked when given,
+// and anonymous access is allowed unless the policy requires authentication.
+async fn authenticate(
+    credentials: Option<(ByteBuf, ByteBuf)>,
+    server: &Server,
+) -> Result<Option<String>> {
+    let authenticator = server.authenticator.as_ref();
+    if let Some((username, password)) = credentials {
+        let auth = Auth::UsernamePassword {
+            username: &username,
+            password: &password,
+        };
+        return match authenticator.authenticate(auth).await {
+            AuthResult::Accept => Ok(Some(String::from_utf8_lossy(&username).into_owned())),
+            AuthResult::Deny => Err(Error::AuthFailure),
+        };
+    }
+    if server.auth_policy == AuthPolicy::RequireAuth {
+        return Err(Error::ProtocolError("proxy authentication required"));
+    }
+    match authenticator.authenticate(Auth::None).await {
+        AuthResult::Accept => Ok(None),
+        AuthResult::Deny => Err(Error::ProtocolError("proxy authentication required")),
+    }
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &str,
+) -> io::Result<()> {
+    let response = format!("HTTP/1.1 {status}\r\n{headers}\r\n");
+    writer.write_all(response.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+// Keepalive enables TCP keepalive probes on client and upstream connections. Parameters
+// that are `None` keep the OS defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Keepalive {
+    // Idle time before the first probe is sent.
+    pub time: Option<Duration>,
+    // Time between unanswered probes.
+    pub interval: Option<Duration>,
+    // Unanswered probes after which the connection is dropped.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    pub(crate) fn apply<'s>(&self, socket: impl Into<SockRef<'s>>) -> io::Result<()> {
+        let mut params = TcpKeepalive::new();
+        if let Some(time) = self.time {
+            params = params.with_time(time);
+        }
+        // The interval and probe count cannot be set on every platform, where the OS
+        // defaults stay in effect.
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "windows"
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                params = params.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                params = params.with_retries(retries);
+            }
+        }
+        socket.into().set_tcp_keepalive(&params)
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+// ListenAddr is where the server accepts clients. It is written as `tcp://ADDR:PORT`
+// (or just `ADDR:PORT`) and `unix:///path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        let addr = s.strip_prefix("tcp://").unwrap_or(s);
+        addr.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|e| format!("invalid listen address `{s}`: {e}"))
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "tcp://{addr}"),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+// ClientAddr is the address of a connected client.
+#[derive(Debug, Clone)]
+pub enum ClientAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix,
+}
+
+impl ClientAddr {
+    // Returns the client's IP address, which does not exist for Unix socket clients.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Tcp(addr) => Some(addr.ip()),
+            #[cfg(unix)]
+            ClientAddr::Unix => None,
+        }
+    }
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ClientAddr::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    // Binds a listener. For IPv6 TCP addresses `ipv6_only` decides whether IPv4 clients
+    // are accepted too (as IPv4-mapped addresses), so that `[::]` serves both families.
+    // `reuse_port` lets several processes share a TCP port, with the kernel spreading
+    // connections among them. `backlog` is the queue length passed to listen(2).
+    pub fn bind(
+        addr: &ListenAddr,
+        ipv6_only: bool,
+        reuse_port: bool,
+        backlog: u32,
+    ) -> io::Result<Listener> {
+        // The kernel silently caps the queue at net.core.somaxconn (Linux) or
+        // kern.ipc.somaxconn (BSD, macOS), so a larger value needs that raised too.
+        let backlog = i32::try_from(backlog).unwrap_or(i32::MAX);
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(bind_tcp(
+                *addr, ipv6_only, reuse_port, backlog,
+            )?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(bind_unix(path, backlog)?))
+            }
+        }
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Stream, ClientAddr)>> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = ready!(listener.poll_accept(cx))?;
+                // Report IPv4 clients of a dual-stack listener by their IPv4 address.
+                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                Poll::Ready(Ok((Stream::Tcp(stream), ClientAddr::Tcp(addr))))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = ready!(listener.poll_accept(cx))?;
+                Poll::Ready(Ok((Stream::Unix(stream), ClientAddr::Unix)))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().unwrap_or(std::path::Path::new(""));
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+}
+
+fn bind_tcp(
+    addr: SocketAddr,
+    ipv6_only: bool,
+    reuse_port: bool,
+    backlog: i32,
+) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // Lets a restarted server bind while connections of the previous one linger in
+    // TIME_WAIT. On Windows SO_REUSEADDR would allow stealing a port in use instead.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, backlog: i32) -> io::Result<UnixListener> {
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket2::SockAddr::unix(path)?)?;
+    socket.listen(backlog)?;
+    UnixListener::from_std(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+// Removes a socket file left behind by a previous run. Other kinds of files are kept
+// so that a typo in the path cannot delete them.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// File descriptor of the first socket passed by systemd (SD_LISTEN_FDS_START); the
+// others follow it.
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+// Returns the listening sockets passed by systemd socket activation, which is empty
+// without socket activation. Each socket may be a TCP or a Unix one, as configured by the
+// ListenStream= lines of the socket unit. See sd_listen_fds(3) for the protocol.
+#[cfg(unix)]
+pub(crate) fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    use std::os::unix::io::FromRawFd;
+
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(Vec::new());
+    };
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let n_fds: i32 = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+
+    // The sockets must not be inherited again by child processes.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(n_fds.max(0)) {
+        // SAFETY: systemd guarantees that the descriptors are open and owned by this process.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if socket.r#type()? != Type::STREAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("descriptor {fd} is not a stream socket"),
+            ));
+        }
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+        if local_addr.as_socket().is_some() {
+            listeners.push(Listener::Tcp(TcpListener::from_std(socket.into())?));
+        } else if local_addr.is_unix() {
+            listeners.push(Listener::Unix(UnixListener::from_std(socket.into())?));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("descriptor {fd} is neither a TCP nor a Unix socket"),
+            ));
+        }
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
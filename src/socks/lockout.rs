@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use slog::{info, warn};
+
+// LockoutPolicy describes when a client IP is locked out after failed logins.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    // Number of failures within `window` that triggers a lockout.
+    pub max_failures: u32,
+    pub window: Duration,
+    // How long a locked-out IP is rejected.
+    pub cooldown: Duration,
+}
+
+struct Entry {
+    failures: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, policy: &LockoutPolicy, now: Instant) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => self.window_start + policy.window <= now,
+        }
+    }
+}
+
+// FailureTable tracks failed username/password logins per client IP.
+#[derive(Default)]
+pub(crate) struct FailureTable {
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl FailureTable {
+    // Returns true if `ip` is currently locked out.
+    pub fn is_locked_out(&self, ip: IpAddr, policy: &LockoutPolicy, logger: &slog::Logger) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&ip) else {
+            return false;
+        };
+        if !entry.is_expired(policy, now) {
+            return entry.locked_until.is_some();
+        }
+        if entry.locked_until.is_some() {
+            info!(logger, "client lockout lifted"; "client_ip" => %ip);
+        }
+        entries.remove(&ip);
+        false
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, policy: &LockoutPolicy, logger: &slog::Logger) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        // Prune expired entries lazily so the table does not grow without bound.
+        entries.retain(|ip, entry| {
+            let expired = entry.is_expired(policy, now);
+            if expired && entry.locked_until.is_some() {
+                info!(logger, "client lockout lifted"; "client_ip" => %ip);
+            }
+            !expired
+        });
+
+        let entry = entries.entry(ip).or_insert(Entry {
+            failures: 0,
+            window_start: now,
+            locked_until: None,
+        });
+        if entry.locked_until.is_some() {
+            return;
+        }
+        entry.failures += 1;
+        if entry.failures >= policy.max_failures {
+            entry.locked_until = Some(now + policy.cooldown);
+            warn!(logger, "client locked out";
+                "client_ip" => %ip,
+                "failures" => entry.failures,
+                "cooldown" => ?policy.cooldown,
+            );
+        }
+    }
+}
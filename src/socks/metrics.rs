@@ -0,0 +1,273 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::socks::{Error, FailureReason};
+
+// SessionFailure classifies why a session ended in an error, whichever phase it was in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionFailure {
+    AuthDenied,
+    AuthTimeout,
+    HandshakeTimeout,
+    BindTimeout,
+    Cancelled,
+    UnsupportedVersion,
+    UnsupportedCommand,
+    BadRequest,
+    DestinationDenied,
+    DnsFailure,
+    UpstreamRefused,
+    UpstreamTimeout,
+    UpstreamError,
+    IoError,
+}
+
+impl SessionFailure {
+    const ALL: [SessionFailure; 14] = [
+        SessionFailure::AuthDenied,
+        SessionFailure::AuthTimeout,
+        SessionFailure::HandshakeTimeout,
+        SessionFailure::BindTimeout,
+        SessionFailure::Cancelled,
+        SessionFailure::UnsupportedVersion,
+        SessionFailure::UnsupportedCommand,
+        SessionFailure::BadRequest,
+        SessionFailure::DestinationDenied,
+        SessionFailure::DnsFailure,
+        SessionFailure::UpstreamRefused,
+        SessionFailure::UpstreamTimeout,
+        SessionFailure::UpstreamError,
+        SessionFailure::IoError,
+    ];
+
+    fn of(e: &Error) -> Self {
+        match e {
+            Error::AuthFailure | Error::IdentRejected(_) => SessionFailure::AuthDenied,
+            Error::AuthTimeout => SessionFailure::AuthTimeout,
+            Error::HandshakeTimeout => SessionFailure::HandshakeTimeout,
+            Error::BindTimeout(_) => SessionFailure::BindTimeout,
+            Error::Cancelled => SessionFailure::Cancelled,
+            Error::UnsupportedVersion(_) => SessionFailure::UnsupportedVersion,
+            Error::UnsupportedCommand(_) => SessionFailure::UnsupportedCommand,
+            Error::ProtocolError(_) => SessionFailure::BadRequest,
+            Error::PortNotAllowed(..) | Error::DomainDenied(_) | Error::DestinationBlocked(_) => {
+                SessionFailure::DestinationDenied
+            }
+            Error::ResolveFailed(..) => SessionFailure::DnsFailure,
+            Error::UpstreamConnect(_) | Error::UpstreamProxyRefused(_) => {
+                match FailureReason::of(e) {
+                    FailureReason::Refused | FailureReason::Denied => {
+                        SessionFailure::UpstreamRefused
+                    }
+                    FailureReason::TimedOut => SessionFailure::UpstreamTimeout,
+                    _ => SessionFailure::UpstreamError,
+                }
+            }
+            Error::SourceBindFailed(..)
+            | Error::SourcePortsExhausted(_)
+            | Error::InterfaceBindFailed(..)
+            | Error::UpstreamProxyError(_) => SessionFailure::UpstreamError,
+            Error::IoError(_) => SessionFailure::IoError,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SessionFailure::AuthDenied => "auth_denied",
+            SessionFailure::AuthTimeout => "auth_timeout",
+            SessionFailure::HandshakeTimeout => "handshake_timeout",
+            SessionFailure::BindTimeout => "bind_timeout",
+            SessionFailure::Cancelled => "cancelled",
+            SessionFailure::UnsupportedVersion => "unsupported_version",
+            SessionFailure::UnsupportedCommand => "unsupported_command",
+            SessionFailure::BadRequest => "bad_request",
+            SessionFailure::DestinationDenied => "destination_denied",
+            SessionFailure::DnsFailure => "dns_failure",
+            SessionFailure::UpstreamRefused => "upstream_refused",
+            SessionFailure::UpstreamTimeout => "upstream_timeout",
+            SessionFailure::UpstreamError => "upstream_error",
+            SessionFailure::IoError => "io_error",
+        }
+    }
+}
+
+// Metrics holds the counters exposed in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    auth_failures: AtomicU64,
+    // Indexed like `FailureReason::ALL`.
+    connect_failures: [AtomicU64; FailureReason::ALL.len()],
+    // Indexed like `SessionFailure::ALL`.
+    session_failures: [AtomicU64; SessionFailure::ALL.len()],
+    dns_cache_hits: AtomicU64,
+    dns_cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_traffic(&self, uploaded: u64, downloaded: u64) {
+        self.uploaded_bytes.fetch_add(uploaded, Ordering::Relaxed);
+        self.downloaded_bytes
+            .fetch_add(downloaded, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dns_cache_hit(&self) {
+        self.dns_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dns_cache_miss(&self) {
+        self.dns_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_connect_failure(&self, e: &Error) {
+        let reason = FailureReason::of(e);
+        let index = FailureReason::ALL
+            .iter()
+            .position(|&r| r == reason)
+            .unwrap();
+        self.connect_failures[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_session_failure(&self, e: &Error) {
+        let failure = SessionFailure::of(e);
+        let index = SessionFailure::ALL
+            .iter()
+            .position(|&f| f == failure)
+            .unwrap();
+        self.session_failures[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self, active_connections: usize) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            let value = value.load(Ordering::Relaxed);
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n"
+            );
+        };
+        counter(
+            "musocks_connections_total",
+            "Accepted client connections.",
+            &self.connections,
+        );
+        counter(
+            "musocks_uploaded_bytes_total",
+            "Bytes relayed from clients to upstreams.",
+            &self.uploaded_bytes,
+        );
+        counter(
+            "musocks_downloaded_bytes_total",
+            "Bytes relayed from upstreams to clients.",
+            &self.downloaded_bytes,
+        );
+        counter(
+            "musocks_auth_failures_total",
+            "Rejected username/password logins.",
+            &self.auth_failures,
+        );
+        counter(
+            "musocks_dns_cache_hits_total",
+            "Domain lookups answered from the DNS cache.",
+            &self.dns_cache_hits,
+        );
+        counter(
+            "musocks_dns_cache_misses_total",
+            "Domain lookups that went to the resolver with the DNS cache enabled.",
+            &self.dns_cache_misses,
+        );
+
+        let _ = write!(
+            out,
+            "# HELP musocks_active_connections Connections currently being handled.\n\
+             # TYPE musocks_active_connections gauge\n\
+             musocks_active_connections {active_connections}\n"
+        );
+
+        let _ = write!(
+            out,
+            "# HELP musocks_connect_failures_total Failed upstream connections by reason.\n\
+             # TYPE musocks_connect_failures_total counter\n"
+        );
+        for (reason, value) in FailureReason::ALL.iter().zip(&self.connect_failures) {
+            let reason = reason.as_str();
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "musocks_connect_failures_total{{reason=\"{reason}\"}} {value}"
+            );
+        }
+
+        let _ = write!(
+            out,
+            "# HELP musocks_session_failures_total Sessions that ended in an error by reason.\n\
+             # TYPE musocks_session_failures_total counter\n"
+        );
+        for (failure, value) in SessionFailure::ALL.iter().zip(&self.session_failures) {
+            let reason = failure.as_str();
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "musocks_session_failures_total{{reason=\"{reason}\"}} {value}"
+            );
+        }
+        out
+    }
+}
+
+// Serves `/metrics` over a minimal HTTP/1.0 implementation; Prometheus needs nothing more.
+#[cfg(feature = "metrics")]
+pub(crate) async fn serve(
+    listener: tokio::net::TcpListener,
+    server: std::sync::Arc<crate::socks::Server>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                slog::error!(server.logger, "failed to accept metrics request"; "err" => %e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            // Only the request line matters; cap it so a client cannot make us buffer forever.
+            let mut reader = BufReader::new(reader).take(8192);
+            let mut request_line = String::new();
+            let read = tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                reader.read_line(&mut request_line),
+            );
+            if !matches!(read.await, Ok(Ok(_))) {
+                return;
+            }
+            let response = if request_line.starts_with("GET /metrics ") {
+                let body = server.metrics().render(server.active_connections());
+                format!(
+                    "HTTP/1.0 200 OK\r\n\
+                     Content-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+            } else {
+                "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned()
+            };
+            let _ = writer.write_all(response.as_bytes()).await;
+            let _ = writer.shutdown().await;
+        });
+    }
+}
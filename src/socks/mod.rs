@@ -1,67 +1,669 @@
+mod acl;
+#[cfg(feature = "admin")]
+mod admin;
+mod auth;
+mod chain;
+mod connector;
+mod credentials;
+mod destination;
+mod dns_cache;
+#[cfg(feature = "doh")]
+mod doh;
+mod health;
+pub mod http;
+mod keepalive;
+mod listener;
+mod lockout;
+mod metrics;
+mod nameserver;
+mod proxy_protocol;
+mod ratelimit;
+mod resolver;
+mod reverse_dns;
 mod server;
-mod socks4;
-mod socks5;
+mod sessions;
+pub mod socks4;
+pub mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod throttle;
+#[cfg(feature = "tls")]
+mod tls;
 
 use std::fmt::{self, Display, Formatter};
+use std::hash::BuildHasher;
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
 
-pub use server::Server;
+pub use acl::{Acl, AclLoader};
+pub use auth::{
+    verify_password, AllowAnonymous, AllowedIdents, Auth, AuthPolicy, AuthResult, Authenticator,
+    IdentAuthorizer, StaticAuthenticator,
+};
+pub use chain::UpstreamProxy;
+pub use connector::{Connector, DirectConnector};
+pub use credentials::FileAuthenticator;
+pub use destination::{DomainMatcher, PortPolicy};
+pub use dns_cache::DnsCache;
+#[cfg(feature = "doh")]
+pub use doh::{DohEndpoint, DohResolver};
+pub use keepalive::Keepalive;
+pub use listener::{ClientAddr, ListenAddr};
+pub use lockout::LockoutPolicy;
+pub use metrics::Metrics;
+pub use nameserver::{ClientSubnet, NameserverResolver};
+pub use ratelimit::RateLimit;
+pub use resolver::{AddressFamily, Resolver, SystemResolver};
+pub use server::{
+    proxy_connection, ConnIdFormat, OverloadPolicy, Server, ServerBuilder, SessionStats,
+};
 use thiserror::Error;
-use tokio::net::TcpStream;
+pub use throttle::{BandwidthLimit, BandwidthScope};
+#[cfg(feature = "tls")]
+pub use tls::Tls;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 
 const SOCKS4: u8 = 4;
 const SOCKS5: u8 = 5;
 
 const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_BIND: u8 = 0x02;
+const COMMAND_UDP_ASSOCIATE: u8 = 0x03;
 
-type ByteBuf = smallvec::SmallVec<[u8; 32]>;
+pub type ByteBuf = smallvec::SmallVec<[u8; 32]>;
 
+/// Error returned by the handshake functions and by a failed session.
 #[derive(Error, Debug)]
-enum Error {
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    /// Reading from or writing to the client or an established upstream failed.
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
 
+    /// The client sent a malformed request or violated the protocol.
     #[error("{0}")]
     ProtocolError(&'static str),
+
+    /// The client asked for a command the server does not carry out, such as SOCKS4 BIND.
+    #[error("command {0:#04x} is not supported")]
+    UnsupportedCommand(u8),
+
+    /// The first byte from the client is neither a SOCKS version nor an HTTP method.
+    #[error("unsupported SOCKS version {0:#04x}")]
+    UnsupportedVersion(u8),
+
+    /// The destination domain could not be resolved.
+    #[error("failed to resolve {0}: {1}")]
+    ResolveFailed(String, #[source] io::Error),
+
+    /// Connecting to the destination, or to the upstream proxy in front of it, failed.
+    #[error("failed to connect upstream: {0}")]
+    UpstreamConnect(#[source] io::Error),
+
+    /// The client's username/password was rejected.
+    #[error("authentication failure")]
+    AuthFailure,
+
+    /// The client did not finish authentication in time.
+    #[error("authentication timed out")]
+    AuthTimeout,
+
+    /// The client did not send its whole request within the handshake deadline.
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+
+    /// No inbound connection reached the listener opened for a BIND request within
+    /// `bind_timeout`. Holds the address the listener was bound to.
+    #[error("no inbound connection to {0} for BIND in time")]
+    BindTimeout(SocketAddr),
+
+    /// The destination resolved only to addresses the server refuses to connect to.
+    #[error("destination {0} is blocked")]
+    DestinationBlocked(SocketAddr),
+
+    /// The destination port is not allowed by the port policy.
+    #[error("port {1} of {0} is not allowed")]
+    PortNotAllowed(String, u16),
+
+    /// The destination hostname is on the denylist.
+    #[error("domain {0} is denied")]
+    DomainDenied(String),
+
+    /// The outbound connection could not be bound to the configured source address.
+    #[error("failed to bind to source address {0}: {1}")]
+    SourceBindFailed(IpAddr, #[source] io::Error),
+
+    /// Every port of the configured source port range was in use.
+    #[error("no free source port in {0}")]
+    SourcePortsExhausted(PortRange),
+
+    /// The outbound connection could not be bound to the configured network interface.
+    #[error("failed to bind to interface {0}: {1}")]
+    InterfaceBindFailed(String, #[source] io::Error),
+
+    /// The SOCKS4 ident was rejected by the ident authorizer.
+    #[error("ident {0:?} is not allowed")]
+    IdentRejected(Option<String>),
+
+    /// The session was closed through the admin API.
+    #[error("session cancelled")]
+    Cancelled,
+
+    /// The upstream SOCKS5 proxy answered CONNECT with this failure reply code.
+    #[error("upstream proxy refused the connection (reply {0})")]
+    UpstreamProxyRefused(u8),
+
+    /// Negotiating with the upstream SOCKS5 proxy failed: it does not speak SOCKS5, or it
+    /// accepted none of our auth methods or credentials.
+    #[error("{0}")]
+    UpstreamProxyError(&'static str),
 }
 
-type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
-enum Address {
+// FailureReason classifies why an upstream connection failed, so that every protocol
+// reports and logs the same cause however coarse its own status codes are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FailureReason {
+    Refused,
+    NetworkUnreachable,
+    HostUnreachable,
+    TimedOut,
+    Denied,
+    Other,
+}
+
+impl FailureReason {
+    pub const ALL: [FailureReason; 6] = [
+        FailureReason::Refused,
+        FailureReason::NetworkUnreachable,
+        FailureReason::HostUnreachable,
+        FailureReason::TimedOut,
+        FailureReason::Denied,
+        FailureReason::Other,
+    ];
+
+    pub fn of(e: &Error) -> Self {
+        match e {
+            Error::IoError(e) | Error::UpstreamConnect(e) => Self::of_io(e),
+            Error::DestinationBlocked(_) | Error::DomainDenied(_) => FailureReason::Denied,
+            // Reply codes of RFC 1928.
+            Error::UpstreamProxyRefused(reply) => match reply {
+                0x05 => FailureReason::Refused,
+                0x03 => FailureReason::NetworkUnreachable,
+                0x04 | 0x06 => FailureReason::HostUnreachable,
+                0x02 => FailureReason::Denied,
+                _ => FailureReason::Other,
+            },
+            _ => FailureReason::Other,
+        }
+    }
+
+    fn of_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => return FailureReason::Refused,
+            io::ErrorKind::NetworkUnreachable => return FailureReason::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => return FailureReason::HostUnreachable,
+            // Also raised by `connect_to_upstream` when the connect timeout expires.
+            io::ErrorKind::TimedOut => return FailureReason::TimedOut,
+            _ => {}
+        }
+        // Fall back to the raw errno for errors the standard library does not classify.
+        #[cfg(target_os = "linux")]
+        match e.raw_os_error() {
+            // ENETUNREACH
+            Some(101) => return FailureReason::NetworkUnreachable,
+            // ECONNREFUSED
+            Some(111) => return FailureReason::Refused,
+            // EHOSTUNREACH
+            Some(113) => return FailureReason::HostUnreachable,
+            _ => {}
+        }
+        FailureReason::Other
+    }
+
+    // Label used in metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::Refused => "refused",
+            FailureReason::NetworkUnreachable => "network_unreachable",
+            FailureReason::HostUnreachable => "host_unreachable",
+            FailureReason::TimedOut => "timed_out",
+            FailureReason::Denied => "denied",
+            FailureReason::Other => "other",
+        }
+    }
+
+    // Wording used in logs.
+    pub fn description(self) -> &'static str {
+        match self {
+            FailureReason::Refused => "connection refused",
+            FailureReason::NetworkUnreachable => "network unreachable",
+            FailureReason::HostUnreachable => "host unreachable",
+            FailureReason::TimedOut => "timed out",
+            FailureReason::Denied => "destination denied",
+            FailureReason::Other => "other",
+        }
+    }
+}
+
+// Address is a destination as a client sent it: an IP address or an unresolved domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
     IPv4([u8; 4]),
     IPv6([u8; 16]),
     Domain(ByteBuf),
 }
 
+impl Address {
+    // Returns the IP address, or `None` for a domain.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::IPv4(ip) => Some(IpAddr::from(*ip)),
+            Address::IPv6(ip) => Some(IpAddr::from(*ip)),
+            Address::Domain(_) => None,
+        }
+    }
+
+    // Returns the domain, or `None` for an IP address or a domain that is not UTF-8.
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Address::Domain(d) => std::str::from_utf8(d).ok(),
+            _ => None,
+        }
+    }
+
+    // Returns the socket addresses to connect to for `port`, looking a domain up with
+    // `resolver` the same way the server does.
+    pub async fn to_socket_addrs(
+        &self,
+        port: u16,
+        resolver: &dyn Resolver,
+    ) -> io::Result<Vec<SocketAddr>> {
+        resolve_address(self, port, resolver).await
+    }
+}
+
+impl From<Ipv4Addr> for Address {
+    fn from(ip: Ipv4Addr) -> Self {
+        Address::IPv4(ip.octets())
+    }
+}
+
+impl From<Ipv6Addr> for Address {
+    fn from(ip: Ipv6Addr) -> Self {
+        Address::IPv6(ip.octets())
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => ip.into(),
+            IpAddr::V6(ip) => ip.into(),
+        }
+    }
+}
+
+// Parses an IP address literal, or else a domain, which is checked the same way as one
+// sent by a client.
+impl TryFrom<&str> for Address {
+    type Error = Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(ip.into());
+        }
+        validate_domain(s.as_bytes())?;
+        Ok(Address::Domain(ByteBuf::from_slice(s.as_bytes())))
+    }
+}
+
 impl Display for Address {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Address::IPv4(i) => Ipv4Addr::from(*i).fmt(f),
             Address::IPv6(i) => Ipv6Addr::from(*i).fmt(f),
-            Address::Domain(d) => String::from_utf8_lossy(&d).fmt(f),
+            Address::Domain(d) => String::from_utf8_lossy(d).fmt(f),
         }
     }
 }
 
 // Request represents a request from SOCKS client.
-struct Request {
-    command: u8,
-    address: Address,
+#[derive(Debug)]
+pub struct Request {
+    pub command: u8,
+    pub address: Address,
+    pub port: u16,
+    // Authenticated username, if any.
+    pub user: Option<String>,
+    // Address the upstream connection was made to, once a CONNECT succeeds. For a domain,
+    // this is whichever resolved address accepted the connection. It stays `None` for
+    // connections through an upstream proxy, which resolves domains itself, so the
+    // `upstream_ip` log field is missing for them.
+    pub upstream_addr: Option<SocketAddr>,
+    // USERID field of a SOCKS4 request, unverified, if the client sent a non-empty one.
+    pub ident: Option<String>,
+}
+
+// Destination is what a client asked to connect to. Handshakes report it as soon as the
+// request is parsed, so that a session failing afterwards can still be logged with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    pub address: String,
+    pub port: u16,
+}
+
+impl Destination {
+    fn new(address: &Address, port: u16) -> Self {
+        Destination {
+            address: address.to_string(),
+            port,
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.address.contains(':') {
+            write!(f, "[{}]:{}", self.address, self.port)
+        } else {
+            write!(f, "{}:{}", self.address, self.port)
+        }
+    }
+}
+
+// Upstream is the server-side end of a session established by a handshake.
+pub enum Upstream {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+// Longest domain name allowed by RFC 1035, excluding a trailing dot.
+const MAX_DOMAIN_LEN: usize = 253;
+
+// Checks a requested domain name at parse time, so garbage never reaches the resolver.
+fn validate_domain(domain: &[u8]) -> Result<()> {
+    let Ok(domain) = std::str::from_utf8(domain) else {
+        return Err(Error::ProtocolError("domain name is not utf-8"));
+    };
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    if domain.is_empty() {
+        return Err(Error::ProtocolError("empty domain name"));
+    }
+    if domain.len() > MAX_DOMAIN_LEN {
+        return Err(Error::ProtocolError("domain name is too long"));
+    }
+    if domain.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err(Error::ProtocolError("domain name has invalid characters"));
+    }
+    Ok(())
+}
+
+// Longest random delay before a failure reply that hides its cause.
+const STEALTH_MAX_DELAY: Duration = Duration::from_millis(300);
+
+// Picks the reply to a failed request: `detailed` tells the client why it failed and
+// `generic` does not. Clients that must not learn why get `generic` after a random delay,
+// which also blurs the timing difference between a quick refusal and a slow failure.
+async fn failure_reply<T>(
+    detailed: T,
+    generic: T,
+    server: &Server,
+    client_ip: Option<IpAddr>,
+) -> T {
+    if !server.acl().hides_errors_from(client_ip) {
+        return detailed;
+    }
+    let delay = random_u64() % STEALTH_MAX_DELAY.as_millis() as u64;
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+    generic
+}
+
+// Returns a random number that is good enough for jitter and spreading load, but not for
+// anything security sensitive.
+fn random_u64() -> u64 {
+    // RandomState is seeded randomly per instance.
+    std::collections::hash_map::RandomState::new().hash_one(())
+}
+
+// PortRange is an inclusive range of ports, written as `LOW-HIGH`. It is never empty and
+// never includes port 0, which would let the OS pick a port outside the range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    low: u16,
+    high: u16,
+}
+
+impl PortRange {
+    pub fn new(low: u16, high: u16) -> std::result::Result<Self, String> {
+        if low == 0 || low > high {
+            return Err(format!(
+                "invalid port range {low}-{high}, expected 1 <= LOW <= HIGH"
+            ));
+        }
+        Ok(PortRange { low, high })
+    }
+
+    pub fn low(self) -> u16 {
+        self.low
+    }
+
+    pub fn high(self) -> u16 {
+        self.high
+    }
+
+    fn len(self) -> u32 {
+        u32::from(self.high - self.low) + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range `{s}`, expected LOW-HIGH");
+        let (low, high) = s.split_once('-').ok_or_else(invalid)?;
+        let low: u16 = low.trim().parse().map_err(|_| invalid())?;
+        let high: u16 = high.trim().parse().map_err(|_| invalid())?;
+        PortRange::new(low, high).map_err(|_| invalid())
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.low, self.high)
+    }
+}
+
+// Connects to the destination and returns the stream along with the address it is
+// connected to, unknown when going through an upstream proxy.
+async fn connect_to_upstream(
+    addr: &Address,
     port: u16,
+    server: &Server,
+) -> Result<(TcpStream, Option<SocketAddr>)> {
+    let result = try_connect_to_upstream(addr, port, server).await;
+    if let Err(e) = &result {
+        server.metrics().record_connect_failure(e);
+    }
+    result
+}
+
+async fn try_connect_to_upstream(
+    addr: &Address,
+    port: u16,
+    server: &Server,
+) -> Result<(TcpStream, Option<SocketAddr>)> {
+    if let Address::Domain(domain) = addr {
+        if server.acl().denied_domains.matches(domain) {
+            return Err(Error::DomainDenied(addr.to_string()));
+        }
+    }
+    let connect = server.connector.connect(addr, port, server);
+    let result = match server.connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into())),
+        None => connect.await,
+    };
+    // Connectors report plain I/O errors; tell them apart from failures on the client side.
+    let stream = result.map_err(|e| match e {
+        Error::IoError(e) => Error::UpstreamConnect(e),
+        e => e,
+    })?;
+    let peer_addr = stream.peer_addr().map_err(Error::UpstreamConnect)?;
+    // The peer is the proxy then, not the destination.
+    let upstream_addr = server.upstream_proxy.is_none().then_some(peer_addr);
+    Ok((stream, upstream_addr))
+}
+
+// Opens a TCP connection from the configured source address for the destination's family,
+// pinned to the configured interface if any.
+async fn connect_tcp(addr: SocketAddr, server: &Server) -> Result<TcpStream> {
+    let source = match addr {
+        SocketAddr::V4(_) => server.source_ipv4.map(IpAddr::V4),
+        SocketAddr::V6(_) => server.source_ipv6.map(IpAddr::V6),
+    };
+    let socket = match server.source_ports {
+        Some(ports) => bind_source_port(addr, source, ports, server)?,
+        None => {
+            let socket = new_tcp_socket(addr, server)?;
+            if let Some(source) = source {
+                socket
+                    .bind(SocketAddr::new(source, 0))
+                    .map_err(|e| Error::SourceBindFailed(source, e))?;
+            }
+            socket
+        }
+    };
+    let stream = socket.connect(addr).await?;
+    if let Some(keepalive) = &server.keepalive {
+        keepalive.apply(&stream)?;
+    }
+    Ok(stream)
+}
+
+// Creates a socket for connecting to `addr`, pinned to the configured interface if any.
+fn new_tcp_socket(addr: SocketAddr, server: &Server) -> Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &server.interface {
+        socket2::SockRef::from(&socket)
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|e| Error::InterfaceBindFailed(interface.clone(), e))?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = server;
+    Ok(socket)
+}
+
+// Opens a UDP socket for relaying datagrams to hosts of `addr`'s family, bound to the
+// configured source address for the family and pinned to the configured interface if any.
+fn bind_udp_socket(addr: SocketAddr, server: &Server) -> Result<UdpSocket> {
+    let (domain, source, unspecified) = match addr {
+        SocketAddr::V4(_) => (
+            socket2::Domain::IPV4,
+            server.source_ipv4.map(IpAddr::V4),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ),
+        SocketAddr::V6(_) => (
+            socket2::Domain::IPV6,
+            server.source_ipv6.map(IpAddr::V6),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ),
+    };
+    let socket = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = &server.interface {
+        socket
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|e| Error::InterfaceBindFailed(interface.clone(), e))?;
+    }
+    let ip = source.unwrap_or(unspecified);
+    socket
+        .bind(&SocketAddr::new(ip, 0).into())
+        .map_err(|e| match source {
+            Some(source) => Error::SourceBindFailed(source, e),
+            None => Error::IoError(e),
+        })?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// Binds a socket for `addr` to a free port of `ports`. The search starts at a random port,
+// so that concurrent connections do not all contend for the lowest ones.
+fn bind_source_port(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    ports: PortRange,
+    server: &Server,
+) -> Result<TcpSocket> {
+    let ip = source.unwrap_or(match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    });
+    let start = (random_u64() % u64::from(ports.len())) as u32;
+    for i in 0..ports.len() {
+        let port = ports.low + ((start + i) % ports.len()) as u16;
+        // A socket cannot be bound again after a failed bind, so every attempt needs a
+        // fresh one.
+        let socket = new_tcp_socket(addr, server)?;
+        match socket.bind(SocketAddr::new(ip, port)) {
+            Ok(()) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(Error::SourceBindFailed(ip, e)),
+        }
+    }
+    Err(Error::SourcePortsExhausted(ports))
 }
 
-async fn connect_to_upstream(addr: &Address, port: u16) -> io::Result<TcpStream> {
-    let stream = match addr {
-        Address::IPv4(ip) => TcpStream::connect((Ipv4Addr::from(*ip), port)).await,
-        Address::IPv6(ip) => TcpStream::connect((Ipv6Addr::from(*ip), port)).await,
+// Resolves the address into one or more socket addresses.
+async fn resolve_address(
+    addr: &Address,
+    port: u16,
+    resolver: &dyn Resolver,
+) -> io::Result<Vec<SocketAddr>> {
+    match addr {
+        Address::IPv4(ip) => Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::from(*ip)), port)]),
+        Address::IPv6(ip) => Ok(vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::from(*ip)), port)]),
         Address::Domain(d) => {
-            let Ok(s) = std::str::from_utf8(&d) else {
-                return Err(std::io::Error::other("domain name is not utf-8"));
+            let Ok(s) = std::str::from_utf8(d) else {
+                return Err(io::Error::other("domain name is not utf-8"));
             };
-            TcpStream::connect((s, port)).await
+            let ips = resolver.resolve(s).await?;
+            if ips.is_empty() {
+                return Err(io::Error::other("no address found"));
+            }
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
         }
-    };
-    stream
+    }
+}
+
+// Returns true for loopback, private (RFC 1918), link-local, unique-local and other
+// non-public addresses, which clients must not reach when SSRF protection is enabled.
+fn is_private_destination(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
 }
@@ -0,0 +1,302 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use crate::socks::{is_private_destination, random_u64, resolver, Resolver};
+
+// Upper bound on one query, including a retry over TCP after a truncated answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// The EDNS payload size of DNS Flag Day 2020, which keeps answers from being fragmented.
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const OPTION_CLIENT_SUBNET: u16 = 8;
+const RCODE_NXDOMAIN: u8 = 3;
+
+// ClientSubnet sets how much of a client's address is passed to the nameserver with EDNS
+// Client Subnet (RFC 7871). It is written as `V4PREFIX,V6PREFIX`, such as `24,56`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientSubnet {
+    pub v4_prefix: u8,
+    pub v6_prefix: u8,
+}
+
+impl Default for ClientSubnet {
+    // The prefix lengths RFC 7871 recommends, which identify a network but not a host.
+    fn default() -> Self {
+        ClientSubnet {
+            v4_prefix: 24,
+            v6_prefix: 56,
+        }
+    }
+}
+
+impl ClientSubnet {
+    // Returns the subnet to pass on for `client`. Clients without a public address,
+    // including Unix socket clients, get a zero-length prefix, which also asks the
+    // nameserver not to tailor the answer to the address the query came from.
+    fn of(self, client: Option<IpAddr>) -> IpNet {
+        let (ip, prefix) = match client.map(|ip| ip.to_canonical()) {
+            Some(ip) if !is_private_destination(ip) => match ip {
+                IpAddr::V4(_) => (ip, self.v4_prefix),
+                IpAddr::V6(_) => (ip, self.v6_prefix),
+            },
+            _ => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        };
+        // The prefix lengths are checked when parsed, so this cannot fail.
+        IpNet::new(ip, prefix).unwrap_or_default().trunc()
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let err = || format!("invalid client subnet `{s}`: expected V4PREFIX,V6PREFIX");
+        let (v4, v6) = s.split_once(',').ok_or_else(err)?;
+        let v4_prefix: u8 = v4.trim().parse().map_err(|_| err())?;
+        let v6_prefix: u8 = v6.trim().parse().map_err(|_| err())?;
+        if v4_prefix > 32 || v6_prefix > 128 {
+            return Err(format!("invalid client subnet `{s}`: prefix too long"));
+        }
+        Ok(ClientSubnet {
+            v4_prefix,
+            v6_prefix,
+        })
+    }
+}
+
+impl Display for ClientSubnet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{},{}", self.v4_prefix, self.v6_prefix)
+    }
+}
+
+// NameserverResolver looks domains up by querying a DNS server directly, instead of going
+// through the operating system like `SystemResolver`. Only this resolver can pass the
+// client's subnet on; the system resolver has no way to.
+pub struct NameserverResolver {
+    addr: SocketAddr,
+    client_subnet: Option<ClientSubnet>,
+}
+
+impl NameserverResolver {
+    // Queries the recursive nameserver at `addr`, which must be trusted with the
+    // destinations clients ask for.
+    pub fn new(addr: SocketAddr) -> Self {
+        NameserverResolver {
+            addr,
+            client_subnet: None,
+        }
+    }
+
+    // Passes the subnet of the client each lookup is made for, so that CDNs answer with
+    // servers near the client rather than near the proxy. Off by default, since it tells
+    // the nameserver and the domains' authoritative servers where clients are.
+    pub fn client_subnet(mut self, client_subnet: ClientSubnet) -> Self {
+        self.client_subnet = Some(client_subnet);
+        self
+    }
+
+    async fn query(
+        &self,
+        domain: &str,
+        qtype: u16,
+        subnet: Option<IpNet>,
+    ) -> io::Result<Vec<IpAddr>> {
+        let id = random_u64() as u16;
+        let request = encode_query(id, domain, qtype, subnet)?;
+        let response = tokio::time::timeout(QUERY_TIMEOUT, self.exchange(&request, id))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "nameserver timed out"))??;
+        parse_response(&response, qtype)
+    }
+
+    // Sends a query over UDP, retrying over TCP if the answer did not fit.
+    async fn exchange(&self, request: &[u8], id: u16) -> io::Result<Vec<u8>> {
+        let local = match self.addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.addr).await?;
+        socket.send(request).await?;
+        let mut buf = vec![0; UDP_PAYLOAD_SIZE as usize];
+        let response = loop {
+            let n = socket.recv(&mut buf).await?;
+            // Anything else is a late answer to an earlier query, or spoofed.
+            if n >= 12 && buf[..2] == id.to_be_bytes() {
+                break &buf[..n];
+            }
+        };
+        if response[2] & 0x02 == 0 {
+            return Ok(response.to_vec());
+        }
+
+        let mut stream = TcpStream::connect(self.addr).await?;
+        let len = u16::try_from(request.len()).map_err(io::Error::other)?;
+        stream.write_all(&len.to_be_bytes()).await?;
+        stream.write_all(request).await?;
+        let len = stream.read_u16().await?;
+        let mut response = vec![0; len as usize];
+        stream.read_exact(&mut response).await?;
+        if response.len() < 12 || response[..2] != id.to_be_bytes() {
+            return Err(malformed());
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl Resolver for NameserverResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        let subnet = self.client_subnet();
+        let (v6, v4) = tokio::join!(
+            self.query(domain, TYPE_AAAA, subnet),
+            self.query(domain, TYPE_A, subnet),
+        );
+        merge_families(v6, v4)
+    }
+
+    fn client_subnet(&self) -> Option<IpNet> {
+        (self.client_subnet).map(|subnet| subnet.of(resolver::client_ip()))
+    }
+}
+
+// Combines the answers to an AAAA and an A query, IPv6 first like getaddrinfo(3) does.
+// Many domains lack one family, so this only fails if neither lookup worked.
+pub(crate) fn merge_families(
+    v6: io::Result<Vec<IpAddr>>,
+    v4: io::Result<Vec<IpAddr>>,
+) -> io::Result<Vec<IpAddr>> {
+    match (v6, v4) {
+        (Err(e), Err(_)) => Err(e),
+        (v6, v4) => Ok(v6
+            .unwrap_or_default()
+            .into_iter()
+            .chain(v4.unwrap_or_default())
+            .collect()),
+    }
+}
+
+pub(crate) fn encode_query(
+    id: u16,
+    domain: &str,
+    qtype: u16,
+    subnet: Option<IpNet>,
+) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name");
+    let mut buf = Vec::with_capacity(64);
+    buf.extend(id.to_be_bytes());
+    // A standard query with recursion desired, one question and one additional record.
+    buf.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid());
+        }
+        buf.push(label.len() as u8);
+        buf.extend(label.as_bytes());
+    }
+    buf.push(0);
+    if buf.len() - 12 > 255 {
+        return Err(invalid());
+    }
+    buf.extend(qtype.to_be_bytes());
+    buf.extend(CLASS_IN.to_be_bytes());
+
+    // The OPT pseudo-record carries the payload size and the client subnet.
+    let mut options = Vec::new();
+    if let Some(subnet) = subnet {
+        let (family, octets) = match subnet.addr() {
+            IpAddr::V4(ip) => (1u16, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (2u16, ip.octets().to_vec()),
+        };
+        // Only the bytes covered by the prefix are sent.
+        let octets = &octets[..subnet.prefix_len().div_ceil(8) as usize];
+        options.extend(OPTION_CLIENT_SUBNET.to_be_bytes());
+        options.extend((4 + octets.len() as u16).to_be_bytes());
+        options.extend(family.to_be_bytes());
+        options.extend([subnet.prefix_len(), 0]);
+        options.extend(octets);
+    }
+    buf.push(0);
+    buf.extend(TYPE_OPT.to_be_bytes());
+    buf.extend(UDP_PAYLOAD_SIZE.to_be_bytes());
+    buf.extend([0, 0, 0, 0]);
+    buf.extend((options.len() as u16).to_be_bytes());
+    buf.extend(options);
+    Ok(buf)
+}
+
+// Returns the addresses of type `qtype` in the answer section. Records of other types,
+// such as the CNAMEs leading to them, are skipped.
+pub(crate) fn parse_response(buf: &[u8], qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let read_u16 = |pos: usize| -> io::Result<u16> {
+        let bytes = buf.get(pos..pos + 2).ok_or_else(malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if buf.len() < 12 {
+        return Err(malformed());
+    }
+    match buf[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::Error::new(io::ErrorKind::NotFound, "no such domain")),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "nameserver failed with rcode {rcode}"
+            )))
+        }
+    }
+    let n_questions = read_u16(4)?;
+    let n_answers = read_u16(6)?;
+
+    let mut pos = 12;
+    for _ in 0..n_questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..n_answers {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(pos)?;
+        let class = read_u16(pos + 2)?;
+        let len = read_u16(pos + 8)? as usize;
+        pos += 10;
+        let data = buf.get(pos..pos + len).ok_or_else(malformed)?;
+        pos += len;
+        if rtype != qtype || class != CLASS_IN {
+            continue;
+        }
+        match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => ips.push(IpAddr::V4(Ipv4Addr::from(octets))),
+            (TYPE_AAAA, _, Ok(octets)) => ips.push(IpAddr::V6(Ipv6Addr::from(octets))),
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(ips)
+}
+
+// Returns the position after the name at `pos`, which may end in a compression pointer.
+fn skip_name(buf: &[u8], mut pos: usize) -> io::Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(malformed)?;
+        match len {
+            0 => return Ok(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response")
+}
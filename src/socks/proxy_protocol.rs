@@ -0,0 +1,54 @@
+use std::net::{IpAddr, SocketAddr};
+
+// Fixed 12-byte signature that starts every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+// Version 2 in the high nibble, with the PROXY or LOCAL command in the low one.
+const CMD_LOCAL: u8 = 0x20;
+const CMD_PROXY: u8 = 0x21;
+
+// Address family in the high nibble and STREAM transport in the low one.
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+const UNSPEC: u8 = 0x00;
+
+// Encodes a PROXY protocol v2 header carrying the client's address as the source and the
+// upstream address as the destination. Without a source, such as for Unix socket clients,
+// a LOCAL header is produced so that the receiver uses the real connection addresses.
+pub(crate) fn encode_v2(source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    let Some(source) = source else {
+        header.extend([CMD_LOCAL, UNSPEC, 0, 0]);
+        return header;
+    };
+    header.push(CMD_PROXY);
+    let source_ip = source.ip().to_canonical();
+    let destination_ip = destination.ip().to_canonical();
+    match (source_ip, destination_ip) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(TCP_OVER_IPV4);
+            header.extend(12u16.to_be_bytes());
+            header.extend(src.octets());
+            header.extend(dst.octets());
+        }
+        // Both addresses must be in one family, so IPv4 is mapped into IPv6 when mixed.
+        (src, dst) => {
+            header.push(TCP_OVER_IPV6);
+            header.extend(36u16.to_be_bytes());
+            header.extend(to_ipv6_octets(src));
+            header.extend(to_ipv6_octets(dst));
+        }
+    }
+    header.extend(source.port().to_be_bytes());
+    header.extend(destination.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How often idle buckets are pruned from the table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// RateLimit caps how many new connections a single client IP may open.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    // Sustained connections per second.
+    pub rate: f64,
+    // Connections allowed in a burst above the sustained rate.
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    pruned_at: Instant,
+}
+
+// RateLimiter keeps a token bucket per client IP.
+pub(crate) struct RateLimiter {
+    inner: Mutex<Buckets>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter {
+            inner: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl RateLimiter {
+    // Takes a token for `ip`. Returns false if the client exceeded the rate.
+    pub fn check(&self, ip: IpAddr, limit: &RateLimit) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        // Buckets that have refilled completely carry no state, so drop them.
+        if now.duration_since(inner.pruned_at) >= PRUNE_INTERVAL {
+            inner.buckets.retain(|_, bucket| {
+                bucket.refill(limit, now);
+                bucket.tokens < limit.burst as f64
+            });
+            inner.pruned_at = now;
+        }
+
+        let bucket = inner.buckets.entry(ip).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,113 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+
+use crate::socks::Address;
+
+// Resolver looks up the addresses of a requested domain name before the server connects
+// to it, so that the results go through the same filtering as literal addresses.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, domain: &str) -> io::Result<Vec<IpAddr>>;
+
+    // Returns the client subnet that lookups from the current session are tailored to,
+    // for resolvers that pass it on to the nameserver. Caches keep the answers for
+    // different subnets apart.
+    fn client_subnet(&self) -> Option<IpNet> {
+        None
+    }
+}
+
+tokio::task_local! {
+    // The IP address of the client the current session serves, if it has one.
+    static CLIENT_IP: Option<IpAddr>;
+}
+
+// Runs `session` with `client_ip` visible to the resolver through `client_ip()`.
+pub(crate) async fn with_client_ip<F: Future>(client_ip: Option<IpAddr>, session: F) -> F::Output {
+    CLIENT_IP.scope(client_ip, session).await
+}
+
+// Returns the IP address of the client the current lookup is made for.
+pub(crate) fn client_ip() -> Option<IpAddr> {
+    CLIENT_IP.try_with(|ip| *ip).ok().flatten()
+}
+
+// SystemResolver asks the operating system's resolver, like `TcpStream::connect` does.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        let addrs = tokio::net::lookup_host((domain, 0)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
+// AddressFamily restricts or orders the resolved addresses of a destination by IP family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressFamily {
+    // Uses the addresses in the resolver's order.
+    #[default]
+    Auto,
+    // Only connects over IPv4; SOCKS5 requests for an IPv6 literal are rejected up front.
+    V4Only,
+    // Only connects over IPv6; SOCKS5 requests for an IPv4 literal are rejected up front.
+    V6Only,
+    // Tries addresses of the preferred family first, falling back to the other.
+    PreferV4,
+    PreferV6,
+}
+
+impl AddressFamily {
+    // Filters or reorders `addrs` in place. Fails with HostUnreachable when a restriction
+    // leaves no address.
+    pub(crate) fn apply(self, addrs: &mut Vec<SocketAddr>) -> io::Result<()> {
+        match self {
+            AddressFamily::Auto => {}
+            AddressFamily::V4Only => addrs.retain(SocketAddr::is_ipv4),
+            AddressFamily::V6Only => addrs.retain(SocketAddr::is_ipv6),
+            // Stable sorts, so the resolver's order is kept within each family.
+            AddressFamily::PreferV4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+            AddressFamily::PreferV6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                "no address of the allowed family",
+            ));
+        }
+        Ok(())
+    }
+
+    // Whether a literal address of this kind can ever be connected to.
+    pub(crate) fn allows(self, address: &Address) -> bool {
+        match address {
+            Address::IPv4(_) => self != AddressFamily::V6Only,
+            Address::IPv6(_) => self != AddressFamily::V4Only,
+            Address::Domain(_) => true,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(AddressFamily::Auto),
+            "ipv4-only" => Ok(AddressFamily::V4Only),
+            "ipv6-only" => Ok(AddressFamily::V6Only),
+            "prefer-ipv4" => Ok(AddressFamily::PreferV4),
+            "prefer-ipv6" => Ok(AddressFamily::PreferV6),
+            _ => Err(format!(
+                "unknown address family `{s}` (expected auto, ipv4-only, ipv6-only, \
+                 prefer-ipv4 or prefer-ipv6)"
+            )),
+        }
+    }
+}
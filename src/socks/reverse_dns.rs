@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OnceCell, Semaphore};
+
+// Upper bound on a lookup, since the session waits for it before the handshake.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(1);
+// How long a name, or the lack of one, is reused for the same client.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+// Most clients kept at once; the entry closest to expiry is evicted beyond this.
+const CACHE_CAPACITY: usize = 4096;
+// Most lookups running at once. A lookup holds a blocking thread until getnameinfo returns,
+// even after its client stopped waiting, so many new clients behind a slow resolver must
+// not be able to take the whole blocking pool.
+const MAX_LOOKUPS: usize = 32;
+
+// ClientHosts looks up the host names of client IPs for logging, caching the results so
+// that a busy client costs one PTR query per `CACHE_TTL`.
+pub(crate) struct ClientHosts {
+    cache: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+    // Lookups in progress. Clients of the same IP that arrive meanwhile wait for the
+    // same lookup instead of starting their own.
+    pending: Mutex<HashMap<IpAddr, Arc<OnceCell<Option<String>>>>>,
+    lookups: Arc<Semaphore>,
+}
+
+impl Default for ClientHosts {
+    fn default() -> Self {
+        ClientHosts {
+            cache: Mutex::default(),
+            pending: Mutex::default(),
+            lookups: Arc::new(Semaphore::new(MAX_LOOKUPS)),
+        }
+    }
+}
+
+impl ClientHosts {
+    // Returns the name of `ip`, or `None` if it has none or the lookup failed or timed out.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        if let Some(name) = self.cached(ip) {
+            return name;
+        }
+        let pending = (self.pending.lock().unwrap())
+            .entry(ip)
+            .or_default()
+            .clone();
+        pending.get_or_init(|| self.resolve(ip)).await.clone()
+    }
+
+    fn cached(&self, ip: IpAddr) -> Option<Option<String>> {
+        match self.cache.lock().unwrap().get(&ip) {
+            Some((name, expires_at)) if *expires_at > Instant::now() => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    // Looks `ip` up and caches the result. Waiting for a free lookup slot counts against
+    // the timeout.
+    async fn resolve(&self, ip: IpAddr) -> Option<String> {
+        // Another lookup may have finished since the caller checked the cache.
+        if let Some(name) = self.cached(ip) {
+            self.pending.lock().unwrap().remove(&ip);
+            return name;
+        }
+        let lookup = async {
+            let permit = self.lookups.clone().acquire_owned().await.ok()?;
+            let lookup = tokio::task::spawn_blocking(move || {
+                let name = lookup_addr(ip);
+                drop(permit);
+                name
+            });
+            lookup.await.ok().flatten()
+        };
+        // Timeouts are cached too, so that a slow resolver delays a client only once.
+        let name = tokio::time::timeout(LOOKUP_TIMEOUT, lookup)
+            .await
+            .unwrap_or_default();
+
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        if !cache.contains_key(&ip) && cache.len() >= CACHE_CAPACITY {
+            cache.retain(|_, (_, expires_at)| *expires_at > now);
+            if cache.len() >= CACHE_CAPACITY {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, (_, expires_at))| *expires_at)
+                    .map(|(ip, _)| *ip);
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+        cache.insert(ip, (name.clone(), now + CACHE_TTL));
+        // Removed only once cached, so that no client starts a second lookup in between.
+        self.pending.lock().unwrap().remove(&ip);
+        name
+    }
+}
+
+// Resolves the PTR record of `ip` through the system resolver with getnameinfo(3).
+#[cfg(unix)]
+fn lookup_addr(ip: IpAddr) -> Option<String> {
+    // NI_MAXHOST of glibc and the BSDs.
+    const MAX_HOST: usize = 1025;
+
+    let addr = socket2::SockAddr::from(std::net::SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; MAX_HOST];
+    // SAFETY: `addr` is a valid socket address of the given length and `host` is a
+    // writable buffer of the given length; no service name is requested.
+    let ret = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr().cast(),
+            addr.len(),
+            host.as_mut_ptr(),
+            MAX_HOST as _,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    // SAFETY: getnameinfo wrote a NUL-terminated string into `host` on success.
+    let host = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    host.to_str().ok().map(str::to_owned)
+}
+
+#[cfg(not(unix))]
+fn lookup_addr(_: IpAddr) -> Option<String> {
+    None
+}
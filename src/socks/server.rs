@@ -1,125 +1,2093 @@
+use std::future::Future;
 use std::io;
-use std::net::SocketAddr;
-use std::time::Instant;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
+use ipnet::IpNet;
 use slog::{info, o};
-use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    ReadBuf,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
 
+use crate::socks::dns_cache::CachingResolver;
+use crate::socks::listener::{Listener, Stream};
+use crate::socks::lockout::FailureTable;
+use crate::socks::ratelimit::RateLimiter;
+use crate::socks::reverse_dns::ClientHosts;
+use crate::socks::sessions::{SessionInfo, Sessions};
+use crate::socks::throttle::TokenBucket;
 use crate::socks::*;
 
+const DEFAULT_PORT: u16 = 1080;
+
+// Same as the default capacity of `BufReader`.
+const DEFAULT_RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+// Enough to absorb a burst of connections; see `ServerBuilder::listen_backlog`.
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
 pub struct Server {
     pub logger: slog::Logger,
+
+    // Receives one record per finished session, separately from `logger`. Disabled when
+    // `None`.
+    pub access_logger: Option<slog::Logger>,
+
+    // How the ID that tags every log line of a connection is generated.
+    pub conn_id_format: ConnIdFormat,
+
+    // Looks up the host name of each client and adds it to its log lines as `client_host`.
+    // Off by default, since the lookup delays the session and leaks client IPs to DNS.
+    pub reverse_dns: bool,
+    client_hosts: ClientHosts,
+
+    // Addresses the server accepts clients on. Every listener shares the rest of the
+    // configuration, including the `max_connections` limit.
+    pub listen_addrs: Vec<ListenAddr>,
+    // Keeps the server running when some listen addresses fail to bind, as long as one
+    // binds. Each failure is logged. When false, any failure stops the server.
+    pub ignore_bind_failures: bool,
+
+    // Accepts clients over TLS with this certificate. Clients speak plain TCP when `None`.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Tls>,
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+
+    // Whether an IPv6 listen address accepts IPv6 clients only. When false, listening
+    // on `[::]` accepts both IPv4 and IPv6 clients.
+    pub ipv6_only: bool,
+
+    // Sets SO_REUSEPORT on a TCP listener so that several instances can share the port.
+    pub reuse_port: bool,
+
+    // Length of the queue of connections the kernel has accepted but the server has not.
+    pub listen_backlog: u32,
+
+    // Upper bound on establishing the upstream connection. `None` leaves it to the OS.
+    pub connect_timeout: Option<Duration>,
+
+    // Makes the upstream connections of CONNECT requests.
+    pub connector: Arc<dyn Connector>,
+
+    // Looks up requested domain names.
+    pub resolver: Arc<dyn Resolver>,
+
+    // Caches the resolver's answers. Every lookup goes to the resolver when `None`.
+    pub dns_cache: Option<DnsCache>,
+
+    // Which IP families of a resolved destination are connected to, and in what order.
+    pub address_family: AddressFamily,
+
+    // SOCKS5 proxy that CONNECT requests are forwarded through. Destinations are
+    // connected to directly when `None`.
+    pub upstream_proxy: Option<UpstreamProxy>,
+    // Never resolves requested domain names, like Tor's SOCKS port: they go to the
+    // upstream proxy verbatim, UDP datagrams for domains are dropped, and CONNECTs to
+    // domains fail without an upstream proxy.
+    pub remote_dns: bool,
+
+    // Local addresses that upstream connections and relayed datagrams are sent from, chosen
+    // by the destination's family. The OS picks one when `None`.
+    pub source_ipv4: Option<Ipv4Addr>,
+    pub source_ipv6: Option<Ipv6Addr>,
+
+    // Source ports that upstream connections are made from, for firewalls that match on
+    // them. The OS picks an ephemeral port when `None`.
+    pub source_ports: Option<PortRange>,
+
+    // Network interface that upstream connections are pinned to with SO_BINDTODEVICE.
+    // Linux only, and needs CAP_NET_RAW.
+    pub interface: Option<String>,
+
+    // Sends a PROXY protocol v2 header with the client's address on each upstream TCP
+    // connection before relaying, for backends that want the real client IP.
+    pub send_proxy_protocol: bool,
+
+    // How long a SOCKS5 BIND waits for the inbound connection.
+    pub bind_timeout: Duration,
+
+    // TCP keepalive for client and upstream connections, so that peers that vanish
+    // behind a NAT are noticed. Left to the OS when `None`.
+    pub keepalive: Option<Keepalive>,
+
+    // Tears down a TCP session when neither side sends data for this long. Disabled when `None`.
+    pub idle_timeout: Option<Duration>,
+
+    // Tears down a TCP session when one write to either side blocks for this long, which
+    // happens when a peer stops reading while the other keeps sending. Unlike the idle
+    // timeout, this catches a session that is busy in one direction. Disabled when `None`.
+    pub write_timeout: Option<Duration>,
+
+    // Hard limit on how long a TCP session may last regardless of activity. Unlimited when `None`.
+    pub max_session_duration: Option<Duration>,
+
+    // Size of the buffer each direction of a TCP session is relayed through. Every session
+    // holds two of them, so larger buffers trade memory for fewer reads and writes on fast
+    // links.
+    pub relay_buffer_size: usize,
+
+    // Caps the throughput of each TCP session. Unlimited when `None`.
+    pub bandwidth_limit: Option<BandwidthLimit>,
+
+    // Caps the combined throughput of all TCP sessions in bytes per second, independently
+    // of `bandwidth_limit`. Unlimited when `None`.
+    pub global_bandwidth_limit: Option<NonZeroU64>,
+    global_bucket: Option<Arc<TokenBucket>>,
+
+    // Logs the bytes relayed so far at this interval while a TCP session runs. Disabled
+    // when `None`.
+    pub progress_interval: Option<Duration>,
+
+    pub authenticator: Arc<dyn Authenticator>,
+    pub auth_policy: AuthPolicy,
+
+    // Checks the ident of SOCKS4 requests. Every ident is accepted when `None`.
+    pub ident_authorizer: Option<Arc<dyn IdentAuthorizer>>,
+
+    // Upper bound on the whole SOCKS5 authentication phase, so that stalled clients
+    // cannot hold a connection open.
+    pub auth_timeout: Duration,
+    // Upper bound on the time from accepting a client until it has sent its whole
+    // request, across the version byte, authentication and the request itself, so that a
+    // client cannot hold a connection by sending each step just before its own timeout.
+    // Connecting upstream and waiting for a BIND peer do not count.
+    pub handshake_timeout: Duration,
+
+    // When set, username/password logins are checked against this file instead of
+    // `authenticator`.
+    pub credentials_file: Option<PathBuf>,
+    file_authenticator: Option<Arc<FileAuthenticator>>,
+
+    // Which clients and destinations are allowed. Replaced on SIGHUP by `acl_loader`.
+    acl: RwLock<Arc<Acl>>,
+    // Builds new access rules on SIGHUP. The rules given at startup stay when `None`.
+    pub acl_loader: Option<AclLoader>,
+
+    // Locks out client IPs that fail to log in repeatedly. Disabled when `None`.
+    pub lockout: Option<LockoutPolicy>,
+    failures: FailureTable,
+
+    // Caps new connections per client IP. Unlimited when `None`.
+    pub rate_limit: Option<RateLimit>,
+    rate_limiter: RateLimiter,
+
+    // How long `serve` waits for active connections after a shutdown signal.
+    pub drain_timeout: Duration,
+
+    // Upper bound on concurrently handled connections. Unlimited when `None`.
+    pub max_connections: Option<usize>,
+    pub overload_policy: OverloadPolicy,
+    // Number of active connections beyond which accepting slows down, so that a spike is
+    // spread out instead of hitting `max_connections` at once. Disabled when `None`.
+    pub soft_max_connections: Option<usize>,
+    // How long each accept is delayed while over `soft_max_connections`, unless enough
+    // connections finish first.
+    pub backpressure_delay: Duration,
+    active_connections: AtomicUsize,
+    // Sessions accepted by `serve`, listed on SIGUSR2.
+    sessions: Sessions,
+
+    // Closes new connections right after accepting them while draining, instead of only
+    // failing the health check.
+    pub reject_while_draining: bool,
+    // Toggled by SIGUSR1 to take the server out of a load balancer before shutting down.
+    draining: AtomicBool,
+
+    // Address serving Prometheus metrics at `/metrics`. Disabled when `None`.
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    // Address answering load balancer liveness probes. Disabled when `None`.
+    pub health_addr: Option<SocketAddr>,
+    metrics: Arc<Metrics>,
+
+    // Address serving the admin API, which lists and closes sessions. Disabled when
+    // `None`; it should not be reachable from untrusted networks.
+    #[cfg(feature = "admin")]
+    pub admin_addr: Option<SocketAddr>,
+    // Bearer token the admin API requires. `serve` refuses to start the API without one.
+    #[cfg(feature = "admin")]
+    pub admin_token: Option<String>,
+}
+
+// OverloadPolicy decides what happens to new connections when `max_connections` is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverloadPolicy {
+    // Stop accepting until a connection finishes.
+    #[default]
+    Wait,
+    // Accept and immediately close new connections.
+    Reject,
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(OverloadPolicy::Wait),
+            "reject" => Ok(OverloadPolicy::Reject),
+            _ => Err(format!(
+                "unknown overload policy `{s}` (expected wait or reject)"
+            )),
+        }
+    }
+}
+
+// ConnIdFormat decides how the `id` attached to every log line of a connection is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnIdFormat {
+    // A counter starting at 1, cheap but only unique within one run of one instance.
+    #[default]
+    Counter,
+    // A random UUIDv4, unique across restarts and instances.
+    Uuid,
+}
+
+impl FromStr for ConnIdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "counter" => Ok(ConnIdFormat::Counter),
+            "uuid" => Ok(ConnIdFormat::Uuid),
+            _ => Err(format!(
+                "unknown connection ID format `{s}` (expected counter or uuid)"
+            )),
+        }
+    }
+}
+
+// ConnId is logged as a number or a string depending on the format.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ConnId {
+    Counter(u64),
+    Uuid(uuid::Uuid),
+}
+
+impl slog::Value for ConnId {
+    fn serialize(
+        &self,
+        _: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        match self {
+            ConnId::Counter(n) => serializer.emit_u64(key, *n),
+            ConnId::Uuid(uuid) => serializer.emit_arguments(key, &format_args!("{uuid}")),
+        }
+    }
+}
+
+impl Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnId::Counter(n) => write!(f, "{n}"),
+            ConnId::Uuid(uuid) => write!(f, "{uuid}"),
+        }
+    }
+}
+
+impl FromStr for ConnId {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(n) = s.parse() {
+            return Ok(ConnId::Counter(n));
+        }
+        s.parse()
+            .map(ConnId::Uuid)
+            .map_err(|_| format!("invalid connection ID `{s}`"))
+    }
 }
 
 impl Server {
+    pub fn new(logger: slog::Logger) -> Self {
+        Server {
+            logger,
+            access_logger: None,
+            conn_id_format: ConnIdFormat::default(),
+            reverse_dns: false,
+            client_hosts: ClientHosts::default(),
+            listen_addrs: vec![ListenAddr::Tcp(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                DEFAULT_PORT,
+            ))],
+            ignore_bind_failures: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_acceptor: None,
+            ipv6_only: false,
+            reuse_port: false,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            connect_timeout: None,
+            connector: Arc::new(DirectConnector),
+            resolver: Arc::new(SystemResolver),
+            dns_cache: None,
+            address_family: AddressFamily::default(),
+            upstream_proxy: None,
+            remote_dns: false,
+            source_ipv4: None,
+            source_ipv6: None,
+            source_ports: None,
+            interface: None,
+            send_proxy_protocol: false,
+            bind_timeout: Duration::from_secs(60),
+            keepalive: None,
+            idle_timeout: None,
+            write_timeout: None,
+            max_session_duration: None,
+            relay_buffer_size: DEFAULT_RELAY_BUFFER_SIZE,
+            bandwidth_limit: None,
+            global_bandwidth_limit: None,
+            global_bucket: None,
+            progress_interval: None,
+            authenticator: Arc::new(AllowAnonymous),
+            auth_policy: AuthPolicy::default(),
+            ident_authorizer: None,
+            auth_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            credentials_file: None,
+            file_authenticator: None,
+            acl: RwLock::default(),
+            acl_loader: None,
+            lockout: None,
+            failures: FailureTable::default(),
+            rate_limit: None,
+            rate_limiter: RateLimiter::default(),
+            drain_timeout: Duration::from_secs(30),
+            max_connections: None,
+            overload_policy: OverloadPolicy::default(),
+            soft_max_connections: None,
+            backpressure_delay: Duration::from_millis(100),
+            active_connections: AtomicUsize::new(0),
+            sessions: Sessions::default(),
+            reject_while_draining: false,
+            draining: AtomicBool::new(false),
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+            health_addr: None,
+            metrics: Arc::default(),
+            #[cfg(feature = "admin")]
+            admin_addr: None,
+            #[cfg(feature = "admin")]
+            admin_token: None,
+        }
+    }
+
+    // Re-reads the credentials file and rebuilds the access rules with `acl_loader`. New
+    // connections use the new settings, and the old ones stay in effect for whatever
+    // fails to load.
+    fn reload(&self) {
+        if self.file_authenticator.is_none() && self.acl_loader.is_none() {
+            info!(self.logger, "nothing to reload");
+            return;
+        }
+        if let Some(authenticator) = &self.file_authenticator {
+            match authenticator.reload() {
+                Ok(n_users) => info!(self.logger, "reloaded credentials"; "users" => n_users),
+                Err(e) => slog::error!(self.logger, "failed to reload credentials"; "err" => %e),
+            }
+        }
+        if let Some(loader) = &self.acl_loader {
+            let acl = match loader() {
+                Ok(acl) => acl,
+                Err(e) => {
+                    slog::error!(self.logger, "failed to reload access rules, keeping the old ones";
+                        "err" => %e);
+                    return;
+                }
+            };
+            let mut current = self.acl.write().unwrap();
+            let changes = acl.changes_from(&current);
+            *current = Arc::new(acl);
+            if changes.is_empty() {
+                info!(self.logger, "reloaded access rules, unchanged");
+            } else {
+                info!(self.logger, "reloaded access rules"; "changed" => changes.join(", "));
+            }
+        }
+    }
+
+    // Binds a listener for each listen address. A failure is fatal unless
+    // `ignore_bind_failures` is set, in which case it is logged and the address skipped.
+    fn bind_listeners(&self) -> anyhow::Result<Vec<Listener>> {
+        if self.listen_addrs.is_empty() {
+            anyhow::bail!("no listen address");
+        }
+        let mut listeners = Vec::with_capacity(self.listen_addrs.len());
+        for addr in &self.listen_addrs {
+            match Listener::bind(addr, self.ipv6_only, self.reuse_port, self.listen_backlog) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) if self.ignore_bind_failures => {
+                    slog::error!(self.logger, "failed to bind, skipping";
+                        "listen_addr" => %addr, "err" => %e);
+                }
+                Err(e) => anyhow::bail!("failed to bind {addr}: {e}"),
+            }
+        }
+        if listeners.is_empty() {
+            anyhow::bail!("failed to bind any listen address");
+        }
+        Ok(listeners)
+    }
+
+    // Returns the access rules in effect. A reload does not change the returned rules,
+    // so a check that reads several of them sees one consistent set.
+    pub fn acl(&self) -> Arc<Acl> {
+        self.acl.read().unwrap().clone()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // Whether SIGUSR1 has marked the server as draining.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    #[cfg(feature = "admin")]
+    pub(crate) fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    // Runs the server until the process receives SIGINT or SIGTERM, then drains
+    // connections as `serve_with_shutdown` does.
     pub async fn serve(self) -> anyhow::Result<()> {
-        let port = 1080;
-        let listener = TcpListener::bind(("0.0.0.0", port))
-            .await
-            .map_err(|e| anyhow::anyhow!("failed to bind: {e}"))?;
-        info!(self.logger, "server started"; "port" => port);
+        let shutdown = async {
+            shutdown_signal()
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to listen for signals: {e}"))
+        };
+        self.run(shutdown).await
+    }
+
+    /// Runs the server until `shutdown` completes, which lets an application that embeds
+    /// the server stop it. The listener is then closed and active connections get
+    /// `drain_timeout` to finish before they are aborted, after which this returns.
+    /// SIGINT and SIGTERM are left to the application.
+    ///
+    /// ```
+    /// # #[tokio::main]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// use std::net::SocketAddr;
+    ///
+    /// let (stop, mut stopped) = tokio::sync::watch::channel(());
+    /// let server = musocks::Server::builder()
+    ///     .listen_addr("127.0.0.1:0".parse::<SocketAddr>()?)
+    ///     .build();
+    /// let serving = tokio::spawn(server.serve_with_shutdown(async move {
+    ///     let _ = stopped.changed().await;
+    /// }));
+    ///
+    /// // Later, when the application shuts down:
+    /// stop.send(())?;
+    /// serving.await??;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn serve_with_shutdown(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        self.run(async {
+            shutdown.await;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn run(
+        mut self,
+        shutdown: impl Future<Output = anyhow::Result<()>>,
+    ) -> anyhow::Result<()> {
+        #[cfg(not(target_os = "linux"))]
+        if self.interface.is_some() {
+            anyhow::bail!("binding to a network interface is only supported on Linux");
+        }
+        match (self.soft_max_connections, self.max_connections) {
+            (Some(_), None) => anyhow::bail!("soft_max_connections requires max_connections"),
+            (Some(soft), Some(max)) if soft == 0 || soft > max => {
+                anyhow::bail!("soft_max_connections must be between 1 and max_connections")
+            }
+            _ => {}
+        }
+        #[cfg(feature = "admin")]
+        if self.admin_addr.is_some() && self.admin_token.as_deref().unwrap_or("").is_empty() {
+            anyhow::bail!("the admin API requires admin_token");
+        }
+        if let Some(path) = &self.credentials_file {
+            let authenticator = FileAuthenticator::load(path, &self.logger)
+                .map_err(|e| anyhow::anyhow!("failed to load credentials: {e}"))?;
+            let authenticator = Arc::new(authenticator);
+            self.authenticator = authenticator.clone();
+            self.file_authenticator = Some(authenticator);
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let acceptor = tls
+                .load_acceptor()
+                .map_err(|e| anyhow::anyhow!("failed to load the TLS certificate: {e}"))?;
+            self.tls_acceptor = Some(acceptor);
+        }
+
+        if let Some(cache) = self.dns_cache {
+            self.resolver = Arc::new(CachingResolver::new(
+                self.resolver.clone(),
+                cache,
+                self.metrics.clone(),
+            ));
+        }
+
+        self.global_bucket =
+            (self.global_bandwidth_limit).map(|rate| Arc::new(TokenBucket::new(rate)));
+
+        let inherited = listener::systemd_listeners()
+            .map_err(|e| anyhow::anyhow!("failed to use the sockets passed by systemd: {e}"))?;
+        let listeners = if inherited.is_empty() {
+            self.bind_listeners()?
+        } else {
+            inherited
+        };
+        for listener in &listeners {
+            info!(self.logger, "listening"; "listen_addr" => %listener.local_addr()?);
+        }
+        info!(self.logger, "server started";
+            "listeners" => listeners.len(),
+            "auth_policy" => ?self.auth_policy,
+            "credentials_file" => self.credentials_file.as_ref().map(|p| p.display().to_string()),
+            "connect_timeout" => self.connect_timeout.map(|t| format!("{t:?}")),
+            "idle_timeout" => self.idle_timeout.map(|t| format!("{t:?}")),
+            "max_session_duration" => self.max_session_duration.map(|t| format!("{t:?}")),
+            "max_connections" => self.max_connections,
+            "soft_max_connections" => self.soft_max_connections,
+            "upstream_proxy" => self.upstream_proxy.as_ref().map(|p| p.addr.to_string()),
+            "remote_dns" => self.remote_dns);
+
+        let server = Arc::new(self);
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = server.metrics_addr {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to bind the metrics endpoint: {e}"))?;
+            info!(server.logger, "metrics endpoint started"; "metrics_addr" => %listener.local_addr()?);
+            tokio::spawn(metrics::serve(listener, server.clone()));
+        }
+        #[cfg(feature = "admin")]
+        if let Some(addr) = server.admin_addr {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to bind the admin API: {e}"))?;
+            info!(server.logger, "admin API started"; "admin_addr" => %listener.local_addr()?);
+            tokio::spawn(admin::serve(listener, server.clone()));
+        }
+        let health = match server.health_addr {
+            Some(addr) => {
+                let listener = tokio::net::TcpListener::bind(addr)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("failed to bind the health check: {e}"))?;
+                info!(server.logger, "health check started"; "health_addr" => %listener.local_addr()?);
+                Some(tokio::spawn(health::serve(listener, server.clone())))
+            }
+            None => None,
+        };
+        let limit = server.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let mut backpressure = match (&limit, server.max_connections, server.soft_max_connections) {
+            (Some(limit), Some(max), Some(soft)) => Some(Backpressure {
+                limit: limit.clone(),
+                max,
+                headroom: u32::try_from(max - soft + 1).map_err(|_| {
+                    anyhow::anyhow!("max_connections is too far above soft_max_connections")
+                })?,
+                delay: server.backpressure_delay,
+                deadline: None,
+                engaged: false,
+            }),
+            _ => None,
+        };
+        let mut tasks = JoinSet::new();
+        tokio::pin!(shutdown);
+        let mut reload =
+            Signal::reload().map_err(|e| anyhow::anyhow!("failed to listen for signals: {e}"))?;
+        let mut drain =
+            Signal::drain().map_err(|e| anyhow::anyhow!("failed to listen for signals: {e}"))?;
+        let mut dump =
+            Signal::dump().map_err(|e| anyhow::anyhow!("failed to listen for signals: {e}"))?;
 
-        let mut conn_id = 0;
+        // A u64 does not wrap in any realistic lifetime, even at millions of connections per
+        // second, but wrapping_add keeps the counter from ever panicking in debug builds.
+        // IDs only need to be unique among the connections alive at one time.
+        let mut conn_id: u64 = 0;
+        let mut next_listener = 0;
         loop {
-            conn_id += 1;
-            match listener.accept().await {
-                Ok((conn, addr)) => {
+            tokio::select! {
+                (accepted, permit) = accept(
+                    &listeners,
+                    &mut next_listener,
+                    limit.as_ref(),
+                    server.overload_policy,
+                    backpressure.as_mut(),
+                    &server.logger,
+                ) => {
+                    let (conn, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            slog::error!(server.logger, "failed to accept"; "err" => %err);
+                            continue;
+                        }
+                    };
+                    if server.reject_while_draining && server.is_draining() {
+                        slog::debug!(server.logger, "draining, rejecting"; "client_addr" => %addr);
+                        continue;
+                    }
+                    let permit = match (permit, &limit) {
+                        (None, Some(limit)) => match limit.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                slog::warn!(server.logger, "connection limit reached, rejecting";
+                                    "client_addr" => %addr,
+                                    "active" => server.active_connections.load(Ordering::Relaxed));
+                                continue;
+                            }
+                        },
+                        (permit, _) => permit,
+                    };
+                    if let (Stream::Tcp(conn), Some(keepalive)) = (&conn, &server.keepalive) {
+                        if let Err(err) = keepalive.apply(conn) {
+                            slog::warn!(server.logger, "failed to enable keepalive";
+                                "client_addr" => %addr, "err" => %err);
+                        }
+                    }
+                    conn_id = conn_id.wrapping_add(1);
+                    let id = match server.conn_id_format {
+                        ConnIdFormat::Counter => ConnId::Counter(conn_id),
+                        ConnIdFormat::Uuid => ConnId::Uuid(uuid::Uuid::new_v4()),
+                    };
+                    let local_ip = match &conn {
+                        Stream::Tcp(conn) => conn.local_addr().ok().map(|addr| addr.ip()),
+                        #[cfg(unix)]
+                        Stream::Unix(_) => None,
+                    };
                     let h = Handler {
-                        logger: self.logger.new(o!("id" => conn_id)),
+                        id: Some(id),
+                        local_ip,
+                        logger: server.logger.new(o!("id" => id)),
+                        access_logger: (server.access_logger)
+                            .as_ref()
+                            .map(|logger| logger.new(o!("id" => id))),
+                        server: server.clone(),
                     };
-                    tokio::spawn(h.handle(conn, addr));
+                    tasks.spawn(async move {
+                        // Errors are logged by `handle` itself.
+                        let _ = match conn {
+                            Stream::Tcp(conn) => h.handle(conn, addr).await,
+                            #[cfg(unix)]
+                            Stream::Unix(conn) => h.handle(conn, addr).await,
+                        };
+                        drop(permit);
+                    });
+                }
+                // Reap finished handlers so that the set only holds active connections.
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+                _ = reload.recv() => server.reload(),
+                _ = dump.recv() => server.sessions.dump(&server.logger),
+                _ = drain.recv() => {
+                    let draining = !server.draining.fetch_xor(true, Ordering::Relaxed);
+                    if draining {
+                        info!(server.logger, "draining started, health check fails";
+                            "active" => server.active_connections.load(Ordering::Relaxed));
+                    } else {
+                        info!(server.logger, "draining stopped, health check passes");
+                    }
                 }
-                Err(err) => {
-                    slog::error!(self.logger, "failed to accept"; "err" => %err);
+                result = &mut shutdown => {
+                    result?;
+                    break;
                 }
             }
         }
+
+        drop(listeners);
+        // Failing probes tell load balancers to stop sending clients while we drain.
+        if let Some(health) = health {
+            health.abort();
+        }
+        info!(server.logger, "draining connections"; "active" => tasks.len());
+        let drain = async { while tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(server.drain_timeout, drain)
+            .await
+            .is_err()
+        {
+            slog::warn!(server.logger, "drain timeout expired, aborting connections";
+                "active" => tasks.len());
+            tasks.shutdown().await;
+        }
+        info!(server.logger, "server stopped");
+        Ok(())
+    }
+}
+
+// Accepts a connection from whichever listener has one first. With `OverloadPolicy::Wait`,
+// a permit is acquired before accepting so that excess clients queue in the listen backlog.
+// The listeners are polled starting from `next`, which moves past the one that accepted so
+// that a busy listener cannot starve the others.
+async fn accept(
+    listeners: &[Listener],
+    next: &mut usize,
+    limit: Option<&Arc<Semaphore>>,
+    policy: OverloadPolicy,
+    backpressure: Option<&mut Backpressure>,
+    logger: &slog::Logger,
+) -> (
+    io::Result<(Stream, ClientAddr)>,
+    Option<OwnedSemaphorePermit>,
+) {
+    if let Some(backpressure) = backpressure {
+        backpressure.wait(logger).await;
+    }
+    let permit = match (limit, policy) {
+        (Some(limit), OverloadPolicy::Wait) => limit.clone().acquire_owned().await.ok(),
+        _ => None,
+    };
+    let accepted = std::future::poll_fn(|cx| {
+        for i in 0..listeners.len() {
+            let index = (*next + i) % listeners.len();
+            if let Poll::Ready(accepted) = listeners[index].poll_accept(cx) {
+                *next = index + 1;
+                return Poll::Ready(accepted);
+            }
+        }
+        Poll::Pending
+    });
+    (accepted.await, permit)
+}
+
+// Backpressure delays accepts while more than `soft_max_connections` connections are
+// active. It watches the `max_connections` semaphore: the server is under the soft limit
+// exactly when `headroom` permits are free.
+struct Backpressure {
+    limit: Arc<Semaphore>,
+    max: usize,
+    headroom: u32,
+    delay: Duration,
+    // When the current delay ends. Kept across calls so that the accept being cancelled by
+    // another event in `serve` does not start the delay over.
+    deadline: Option<Instant>,
+    engaged: bool,
+}
+
+impl Backpressure {
+    // Returns at once while under the soft limit. Over it, returns as soon as enough
+    // connections finish, or after `delay` at the latest; clients are slowed down but
+    // never turned away here.
+    async fn wait(&mut self, logger: &slog::Logger) {
+        if self.limit.try_acquire_many(self.headroom).is_err() {
+            if !self.engaged {
+                self.engaged = true;
+                slog::warn!(logger, "backpressure engaged, delaying accepts";
+                    "active" => self.active());
+            }
+            let deadline = *self
+                .deadline
+                .get_or_insert_with(|| Instant::now() + self.delay);
+            let freed = self.limit.acquire_many(self.headroom);
+            let freed = tokio::time::timeout_at(deadline.into(), freed)
+                .await
+                .is_ok();
+            self.deadline = None;
+            if !freed {
+                return;
+            }
+        }
+        if self.engaged {
+            self.engaged = false;
+            info!(logger, "backpressure disengaged"; "active" => self.active());
+        }
+    }
+
+    // Connections holding a permit.
+    fn active(&self) -> usize {
+        self.max - self.limit.available_permits()
+    }
+}
+
+// Signal receives a Unix signal the server reacts to while running. It never fires on
+// other platforms.
+#[cfg(unix)]
+struct Signal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl Signal {
+    // SIGHUP asks the server to reload its configuration.
+    fn reload() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::hangup()).map(Signal)
+    }
+
+    // SIGUSR1 toggles draining.
+    fn drain() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::user_defined1()).map(Signal)
+    }
+
+    // SIGUSR2 logs the active sessions.
+    fn dump() -> io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::user_defined2()).map(Signal)
+    }
+
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct Signal;
+
+#[cfg(not(unix))]
+impl Signal {
+    fn reload() -> io::Result<Self> {
+        Ok(Signal)
+    }
+
+    fn drain() -> io::Result<Self> {
+        Ok(Signal)
+    }
+
+    fn dump() -> io::Result<Self> {
+        Ok(Signal)
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+// Resolves when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await
+    }
+}
+
+// ServerBuilder configures a `Server`. Options that are not set keep the defaults
+// of `Server::new`, and the logger discards everything unless one is given.
+pub struct ServerBuilder {
+    server: Server,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        ServerBuilder {
+            server: Server::new(logger),
+        }
+    }
+
+    pub fn logger(mut self, logger: slog::Logger) -> Self {
+        self.server.logger = logger;
+        self
+    }
+
+    pub fn access_logger(mut self, logger: slog::Logger) -> Self {
+        self.server.access_logger = Some(logger);
+        self
+    }
+
+    pub fn conn_id_format(mut self, format: ConnIdFormat) -> Self {
+        self.server.conn_id_format = format;
+        self
+    }
+
+    pub fn reverse_dns(mut self, reverse_dns: bool) -> Self {
+        self.server.reverse_dns = reverse_dns;
+        self
+    }
+
+    // Replaces the listen addresses with `listen_addr`.
+    pub fn listen_addr(mut self, listen_addr: impl Into<ListenAddr>) -> Self {
+        self.server.listen_addrs = vec![listen_addr.into()];
+        self
+    }
+
+    // Replaces the listen addresses, so that the server accepts clients on each of them.
+    pub fn listen_addrs(mut self, listen_addrs: Vec<ListenAddr>) -> Self {
+        self.server.listen_addrs = listen_addrs;
+        self
+    }
+
+    pub fn ignore_bind_failures(mut self, ignore_bind_failures: bool) -> Self {
+        self.server.ignore_bind_failures = ignore_bind_failures;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.server.tls = Some(tls);
+        self
+    }
+
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.server.ipv6_only = ipv6_only;
+        self
+    }
+
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.server.reuse_port = reuse_port;
+        self
+    }
+
+    // Sets the listen(2) backlog. The kernel silently lowers it to the somaxconn limit, so
+    // raising it past that (128 on older Linux, 4096 since 5.4) needs the sysctl raised too.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.server.listen_backlog = backlog;
+        self
+    }
+
+    // Sets the IP address of the first TCP listen address, keeping its port. The server
+    // listens on that address only afterwards.
+    pub fn bind_addr(mut self, bind_addr: IpAddr) -> Self {
+        let port = match self.server.listen_addrs.first() {
+            Some(ListenAddr::Tcp(addr)) => addr.port(),
+            _ => DEFAULT_PORT,
+        };
+        self.server.listen_addrs = vec![ListenAddr::Tcp(SocketAddr::new(bind_addr, port))];
+        self
+    }
+
+    // Sets the port of the first TCP listen address, keeping its IP address. The server
+    // listens on that address only afterwards.
+    pub fn port(mut self, port: u16) -> Self {
+        let ip = match self.server.listen_addrs.first() {
+            Some(ListenAddr::Tcp(addr)) => addr.ip(),
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        self.server.listen_addrs = vec![ListenAddr::Tcp(SocketAddr::new(ip, port))];
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.server.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn connector(mut self, connector: Arc<dyn Connector>) -> Self {
+        self.server.connector = connector;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.server.resolver = resolver;
+        self
+    }
+
+    pub fn dns_cache(mut self, cache: DnsCache) -> Self {
+        self.server.dns_cache = Some(cache);
+        self
+    }
+
+    pub fn address_family(mut self, family: AddressFamily) -> Self {
+        self.server.address_family = family;
+        self
+    }
+
+    pub fn upstream_proxy(mut self, proxy: UpstreamProxy) -> Self {
+        self.server.upstream_proxy = Some(proxy);
+        self
+    }
+
+    pub fn remote_dns(mut self, remote_dns: bool) -> Self {
+        self.server.remote_dns = remote_dns;
+        self
+    }
+
+    // Sets the source address for upstream connections of the address's family.
+    pub fn source_ip(mut self, ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => self.server.source_ipv4 = Some(ip),
+            IpAddr::V6(ip) => self.server.source_ipv6 = Some(ip),
+        }
+        self
+    }
+
+    pub fn source_ports(mut self, ports: PortRange) -> Self {
+        self.server.source_ports = Some(ports);
+        self
+    }
+
+    pub fn interface(mut self, interface: impl Into<String>) -> Self {
+        self.server.interface = Some(interface.into());
+        self
+    }
+
+    pub fn send_proxy_protocol(mut self, enable: bool) -> Self {
+        self.server.send_proxy_protocol = enable;
+        self
+    }
+
+    pub fn bind_timeout(mut self, timeout: Duration) -> Self {
+        self.server.bind_timeout = timeout;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.server.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.server.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.server.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_session_duration(mut self, limit: Duration) -> Self {
+        self.server.max_session_duration = Some(limit);
+        self
+    }
+
+    pub fn relay_buffer_size(mut self, size: usize) -> Self {
+        self.server.relay_buffer_size = size;
+        self
+    }
+
+    pub fn bandwidth_limit(mut self, limit: BandwidthLimit) -> Self {
+        self.server.bandwidth_limit = Some(limit);
+        self
+    }
+
+    pub fn global_bandwidth_limit(mut self, rate: NonZeroU64) -> Self {
+        self.server.global_bandwidth_limit = Some(rate);
+        self
+    }
+
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.server.progress_interval = Some(interval);
+        self
+    }
+
+    pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.server.authenticator = authenticator;
+        self
+    }
+
+    pub fn auth_policy(mut self, policy: AuthPolicy) -> Self {
+        self.server.auth_policy = policy;
+        self
+    }
+
+    pub fn ident_authorizer(mut self, authorizer: Arc<dyn IdentAuthorizer>) -> Self {
+        self.server.ident_authorizer = Some(authorizer);
+        self
+    }
+
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.server.auth_timeout = timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.server.handshake_timeout = timeout;
+        self
+    }
+
+    pub fn credentials_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.server.credentials_file = Some(path.into());
+        self
+    }
+
+    pub fn acl(mut self, acl: Acl) -> Self {
+        *self.server.acl.get_mut().unwrap() = Arc::new(acl);
+        self
+    }
+
+    pub fn acl_loader(mut self, loader: AclLoader) -> Self {
+        self.server.acl_loader = Some(loader);
+        self
+    }
+
+    pub fn block_private_destinations(mut self, block: bool) -> Self {
+        self.acl_mut().block_private_destinations = block;
+        self
+    }
+
+    pub fn port_policy(mut self, policy: PortPolicy) -> Self {
+        self.acl_mut().port_policy = policy;
+        self
+    }
+
+    pub fn denied_domains(mut self, domains: DomainMatcher) -> Self {
+        self.acl_mut().denied_domains = domains;
+        self
+    }
+
+    pub fn allowed_clients(mut self, networks: Vec<IpNet>) -> Self {
+        self.acl_mut().allowed_clients = networks;
+        self
+    }
+
+    pub fn stealth_errors(mut self, stealth_errors: bool) -> Self {
+        self.acl_mut().stealth_errors = stealth_errors;
+        self
+    }
+
+    pub fn trusted_clients(mut self, networks: Vec<IpNet>) -> Self {
+        self.acl_mut().trusted_clients = networks;
+        self
+    }
+
+    fn acl_mut(&mut self) -> &mut Acl {
+        Arc::make_mut(self.server.acl.get_mut().unwrap())
+    }
+
+    pub fn lockout(mut self, policy: LockoutPolicy) -> Self {
+        self.server.lockout = Some(policy);
+        self
+    }
+
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.server.rate_limit = Some(limit);
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.server.drain_timeout = timeout;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.server.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn overload_policy(mut self, policy: OverloadPolicy) -> Self {
+        self.server.overload_policy = policy;
+        self
+    }
+
+    pub fn soft_max_connections(mut self, soft_max_connections: usize) -> Self {
+        self.server.soft_max_connections = Some(soft_max_connections);
+        self
+    }
+
+    pub fn backpressure_delay(mut self, delay: Duration) -> Self {
+        self.server.backpressure_delay = delay;
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.server.metrics_addr = Some(addr);
+        self
+    }
+
+    pub fn reject_while_draining(mut self, reject: bool) -> Self {
+        self.server.reject_while_draining = reject;
+        self
+    }
+
+    pub fn health_addr(mut self, addr: SocketAddr) -> Self {
+        self.server.health_addr = Some(addr);
+        self
+    }
+
+    #[cfg(feature = "admin")]
+    pub fn admin_addr(mut self, addr: SocketAddr) -> Self {
+        self.server.admin_addr = Some(addr);
+        self
+    }
+
+    #[cfg(feature = "admin")]
+    pub fn admin_token(mut self, token: String) -> Self {
+        self.server.admin_token = Some(token);
+        self
+    }
+
+    pub fn build(self) -> Server {
+        self.server
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+// SessionStats describes a session that completed, for embedders to record as they like;
+// `serve` writes the same figures to the access log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionStats {
+    // "socks4", "socks5" or "http".
+    pub protocol: Option<&'static str>,
+    // The authenticated user, if the session required authentication.
+    pub user: Option<String>,
+    // What the client asked to connect to, unless the session ended before a request.
+    pub destination: Option<Destination>,
+    // From the client to the destination.
+    pub uploaded_bytes: u64,
+    // From the destination to the client.
+    pub downloaded_bytes: u64,
+    // From accepting the connection until the session ended.
+    pub elapsed: Duration,
+}
+
+// Proxies one client connection accepted outside of `Server::serve`, such as by an
+// embedding application's own listener or by a test. The connection goes through the same
+// admission checks, protocol detection, handshake, relay and logging as one accepted by
+// `serve`. Settings that `serve` loads on startup (the credentials file, TLS certificate,
+// DNS cache and global bandwidth limit) do not take effect here.
+pub async fn proxy_connection(
+    stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    peer_addr: ClientAddr,
+    server: Arc<Server>,
+) -> Result<SessionStats> {
+    let handler = Handler {
+        id: None,
+        local_ip: None,
+        logger: server.logger.clone(),
+        access_logger: server.access_logger.clone(),
+        server,
+    };
+    handler.handle(stream, peer_addr).await
+}
+
 struct Handler {
+    // Identifies the session in `Server::sessions`; `None` keeps it out of the registry.
+    id: Option<ConnId>,
+    // Address the client connected to, where BIND and UDP ASSOCIATE listen. `None` for
+    // Unix sockets and `proxy_connection`, which leave it to the OS.
+    local_ip: Option<IpAddr>,
     logger: slog::Logger,
+    access_logger: Option<slog::Logger>,
+    server: Arc<Server>,
+}
+
+// AccessRecord collects what the access log reports about a session as it progresses.
+#[derive(Default)]
+struct AccessRecord {
+    // "socks4", "socks5" or "http".
+    protocol: Option<&'static str>,
+    user: Option<String>,
+    // Set once the handshake has parsed the request, even if the session fails later.
+    destination: Option<Destination>,
+    uploaded_bytes: u64,
+    downloaded_bytes: u64,
+    // How a session without an error ended. Sessions dropped before this is set, such as
+    // rate-limited ones, are not logged so that a flooding client cannot flood the log.
+    status: Option<&'static str>,
 }
 
 impl Handler {
-    async fn handle(self, client: TcpStream, client_addr: SocketAddr) {
-        if let Err(e) = self.handle_conn(client, client_addr).await {
-            slog::error!(self.logger, "proxy failed"; "err" => %e);
+    async fn handle(
+        mut self,
+        client: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        client_addr: ClientAddr,
+    ) -> Result<SessionStats> {
+        self.server.metrics.record_connection();
+        self.server
+            .active_connections
+            .fetch_add(1, Ordering::Relaxed);
+        let started_at = Instant::now();
+        let cancel = (self.id).map(|id| self.server.sessions.insert(id, client_addr.clone()));
+        if let (true, Some(ip)) = (self.server.reverse_dns, client_addr.ip()) {
+            if let Some(host) = self.server.client_hosts.lookup(ip).await {
+                self.logger = self.logger.new(o!("client_host" => host.clone()));
+                self.access_logger =
+                    (self.access_logger).map(|logger| logger.new(o!("client_host" => host)));
+            }
+        }
+        let mut record = AccessRecord::default();
+        let session = self.handle_conn(client, &client_addr, &mut record);
+        let session = resolver::with_client_ip(client_addr.ip(), session);
+        let result = match cancel {
+            Some(cancel) => tokio::select! {
+                result = session => result,
+                _ = cancel.notified() => Err(Error::Cancelled),
+            },
+            None => session.await,
+        };
+        if let Some(info) = self.id.and_then(|id| self.server.sessions.remove(id)) {
+            // A cancelled relay never got to report its bytes.
+            if let (Err(Error::Cancelled), Some(traffic)) = (&result, &info.traffic) {
+                (record.uploaded_bytes, record.downloaded_bytes) = traffic.totals();
+            }
+        }
+        self.server
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+        let stats = SessionStats {
+            protocol: record.protocol,
+            user: record.user,
+            destination: record.destination,
+            uploaded_bytes: record.uploaded_bytes,
+            downloaded_bytes: record.downloaded_bytes,
+            elapsed: started_at.elapsed(),
+        };
+        if let Some(access_logger) = &self.access_logger {
+            let status = match &result {
+                Ok(()) => record.status,
+                Err(_) => Some("failed"),
+            };
+            if let Some(status) = status {
+                let err = result.as_ref().err().map(|e| e.to_string());
+                info!(access_logger, "access";
+                    "client_addr" => %client_addr,
+                    "user" => stats.user.as_deref().unwrap_or("anonymous"),
+                    "protocol" => stats.protocol,
+                    "destination" => stats.destination.as_ref().map(|d| d.address.as_str()),
+                    "port" => stats.destination.as_ref().map(|d| d.port),
+                    "uploaded_bytes" => stats.uploaded_bytes,
+                    "downloaded_bytes" => stats.downloaded_bytes,
+                    "duration" => ?stats.elapsed,
+                    "status" => status,
+                    "err" => err,
+                );
+            }
+        }
+        // Failures after the request was parsed are logged with its destination.
+        let logger = match &stats.destination {
+            Some(dst) => self.logger.new(o!("dst" => dst.to_string())),
+            None => self.logger.clone(),
+        };
+        if let Err(e) = &result {
+            self.server.metrics.record_session_failure(e);
+        }
+        match &result {
+            Ok(()) => {}
+            Err(e @ Error::AuthFailure) => {
+                self.server.metrics.record_auth_failure();
+                if let Some(policy) = &self.server.lockout {
+                    let failures = &self.server.failures;
+                    if let Some(ip) = client_addr.ip() {
+                        failures.record_failure(ip, policy, &self.logger);
+                    }
+                }
+                slog::error!(logger, "proxy failed"; "err" => %e);
+            }
+            Err(e @ (Error::AuthTimeout | Error::HandshakeTimeout)) => {
+                slog::warn!(logger, "closing stalled client"; "err" => %e);
+            }
+            Err(Error::Cancelled) => {
+                info!(logger, "session cancelled");
+            }
+            Err(Error::BindTimeout(bind_addr)) => {
+                slog::warn!(logger, "bind expired";
+                    "client_addr" => %client_addr,
+                    "bind_addr" => %bind_addr,
+                    "bind_timeout" => ?self.server.bind_timeout);
+            }
+            Err(Error::PortNotAllowed(destination, port)) => {
+                slog::warn!(logger, "destination port denied";
+                    "destination" => destination, "port" => port);
+            }
+            Err(Error::DomainDenied(domain)) => {
+                slog::warn!(logger, "destination domain denied"; "domain" => domain);
+            }
+            Err(Error::IdentRejected(ident)) => {
+                slog::warn!(logger, "ident rejected"; "ident" => ident);
+            }
+            Err(e) => match FailureReason::of(e) {
+                FailureReason::Other => slog::error!(logger, "proxy failed"; "err" => %e),
+                reason => slog::error!(logger, "proxy failed";
+                    "reason" => reason.description(), "err" => %e),
+            },
         }
+        result.map(|()| stats)
     }
 
-    async fn handle_conn(&self, client: TcpStream, client_addr: SocketAddr) -> Result<()> {
+    async fn handle_conn(
+        &self,
+        client: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        client_addr: &ClientAddr,
+        record: &mut AccessRecord,
+    ) -> Result<()> {
         let started_at = Instant::now();
-        info!(self.logger, "proxy start"; "client_addr" => client_addr);
+        if let (Some(limit), Some(ip)) = (&self.server.rate_limit, client_addr.ip()) {
+            if !self.server.rate_limiter.check(ip, limit) {
+                // Logged at debug level since a flooding client would flood the log, too.
+                slog::debug!(self.logger, "rate limit exceeded"; "client_addr" => %client_addr);
+                return Ok(());
+            }
+        }
+        info!(self.logger, "proxy start";
+            "client_addr" => %client_addr,
+            "active" => self.server.active_connections.load(Ordering::Relaxed));
 
-        let (mut client_reader, mut client_writer) = {
-            let (r, w) = client.into_split();
-            (BufReader::new(r), w)
-        };
+        if let Some(ip) = client_addr.ip() {
+            if !self.server.acl().is_client_allowed(ip) {
+                slog::warn!(self.logger, "access denied"; "client_addr" => %client_addr);
+                record.status = Some("denied");
+                return Ok(());
+            }
+        }
+
+        if let (Some(policy), Some(ip)) = (&self.server.lockout, client_addr.ip()) {
+            if self.server.failures.is_locked_out(ip, policy, &self.logger) {
+                return Err(Error::ProtocolError("client is locked out"));
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &self.server.tls_acceptor {
+            // The TLS handshake counts against the time allowed for authentication.
+            let client = tokio::time::timeout(self.server.auth_timeout, acceptor.accept(client))
+                .await
+                .map_err(|_| Error::AuthTimeout)??;
+            return self
+                .handle_session(client, client_addr, record, started_at)
+                .await;
+        }
+        self.handle_session(client, client_addr, record, started_at)
+            .await
+    }
 
-        let preamble = read_preamble(&mut client_reader).await?;
+    // Detects the protocol from the first bytes and runs its handshake.
+    async fn handshake(
+        &self,
+        reader: &mut (impl AsyncBufRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+        client_addr: &ClientAddr,
+        record: &mut AccessRecord,
+    ) -> Result<(Request, Upstream)> {
+        let preamble = read_preamble(reader).await?;
         let version = preamble[0];
 
-        let (request, upstream) = match version {
+        let dst = &mut record.destination;
+        match version {
             SOCKS4 => {
-                socks4::handshake(&mut client_reader, &mut client_writer, preamble[1]).await?
+                record.protocol = Some("socks4");
+                socks4::do_handshake(
+                    reader,
+                    writer,
+                    preamble[1],
+                    client_addr.ip(),
+                    &self.server,
+                    dst,
+                )
+                .await
             }
             SOCKS5 => {
-                socks5::handshake(&mut client_reader, &mut client_writer, preamble[1]).await?
+                record.protocol = Some("socks5");
+                socks5::do_handshake(
+                    reader,
+                    writer,
+                    preamble[1],
+                    client_addr.ip(),
+                    self.local_ip,
+                    &self.server,
+                    dst,
+                )
+                .await
             }
-            _ => return Err(Error::ProtocolError("unsupported SOCKS version")),
+            // An HTTP request starts with an upper-case method name such as CONNECT.
+            b'A'..=b'Z' => {
+                record.protocol = Some("http");
+                http::do_handshake(
+                    reader,
+                    writer,
+                    preamble,
+                    client_addr.ip(),
+                    &self.server,
+                    dst,
+                )
+                .await
+            }
+            _ => Err(Error::UnsupportedVersion(version)),
+        }
+    }
+
+    fn update_session(&self, update: impl FnOnce(&mut SessionInfo)) {
+        if let Some(id) = self.id {
+            self.server.sessions.update(id, update);
+        }
+    }
+
+    // Runs the handshake and relays the session of an admitted client.
+    async fn handle_session(
+        &self,
+        client: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        client_addr: &ClientAddr,
+        record: &mut AccessRecord,
+        started_at: Instant,
+    ) -> Result<()> {
+        let (mut client_reader, mut client_writer) = {
+            let (r, w) = tokio::io::split(client);
+            (
+                BufReader::with_capacity(self.server.relay_buffer_size, r),
+                w,
+            )
         };
 
-        let (upstream_reader, upstream_writer) = {
-            let (r, w) = upstream.into_split();
-            (BufReader::new(r), w)
+        // The deadline only bounds reads from the client, which all happen before the
+        // handshake connects upstream.
+        let deadline = started_at + self.server.handshake_timeout;
+        let (request, upstream) = self
+            .handshake(
+                &mut DeadlineReader::new(&mut client_reader, deadline),
+                &mut client_writer,
+                client_addr,
+                record,
+            )
+            .await
+            .map_err(|e| match e {
+                Error::IoError(e) if e.get_ref().is_some_and(|e| e.is::<DeadlinePassed>()) => {
+                    Error::HandshakeTimeout
+                }
+                e => e,
+            })?;
+
+        record.user = request.user.clone();
+        self.update_session(|info| {
+            info.protocol = record.protocol;
+            info.user = record.user.clone();
+            info.destination = record.destination.clone();
+        });
+        let logger = match &request.user {
+            Some(user) => self.logger.new(o!("user" => user.clone())),
+            None => self.logger.new(o!("user" => "anonymous")),
+        };
+        let logger = match &request.ident {
+            Some(ident) => logger.new(o!("ident" => ident.clone())),
+            None => logger,
+        };
+        let logger = match request.upstream_addr {
+            Some(addr) => logger.new(o!("upstream_ip" => addr.ip().to_string())),
+            None => logger,
         };
 
-        let (uploaded_bytes, downloaded_bytes) = do_proxy(
-            client_reader,
-            client_writer,
-            upstream_reader,
-            upstream_writer,
-        )
-        .await?;
+        let (end, (uploaded_bytes, downloaded_bytes)) = match upstream {
+            Upstream::Tcp(mut upstream) => {
+                if self.server.send_proxy_protocol {
+                    let source = match client_addr {
+                        ClientAddr::Tcp(addr) => Some(*addr),
+                        #[cfg(unix)]
+                        ClientAddr::Unix => None,
+                    };
+                    let header = proxy_protocol::encode_v2(source, upstream.peer_addr()?);
+                    upstream.write_all(&header).await?;
+                }
+                let traffic = Arc::new(Traffic::new(
+                    self.server.bandwidth_limit.as_ref(),
+                    self.server.global_bucket.clone(),
+                    self.server.write_timeout,
+                ));
+                self.update_session(|info| info.traffic = Some(traffic.clone()));
+                let idle_timeout = self.server.idle_timeout;
+                let relay_buffer_size = self.server.relay_buffer_size;
+                let proxy = async {
+                    // Splicing needs the client as a TCP socket, which Unix socket clients
+                    // are not.
+                    #[cfg(target_os = "linux")]
+                    let (client_reader, client_writer) =
+                        match splice::unsplit_tcp(client_reader, client_writer) {
+                            Ok((client, buffered)) => {
+                                // Data the client sent right after the handshake is
+                                // already in our buffer.
+                                upstream.write_all(&buffered).await?;
+                                (traffic.uploaded)
+                                    .fetch_add(buffered.len() as u64, Ordering::Relaxed);
+                                return do_splice_proxy(&client, &upstream, &traffic, idle_timeout)
+                                    .await;
+                            }
+                            Err(halves) => halves,
+                        };
+                    let (upstream_reader, upstream_writer) = {
+                        let (r, w) = upstream.into_split();
+                        (BufReader::with_capacity(relay_buffer_size, r), w)
+                    };
+                    do_proxy(
+                        client_reader,
+                        client_writer,
+                        upstream_reader,
+                        upstream_writer,
+                        &traffic,
+                        idle_timeout,
+                    )
+                    .await
+                };
+                let proxy = with_progress(proxy, &traffic, self.server.progress_interval, &logger);
+                let end = match self.server.max_session_duration {
+                    Some(limit) => tokio::time::timeout(limit, proxy)
+                        .await
+                        .unwrap_or(Ok(SessionEnd::Capped)),
+                    None => proxy.await,
+                };
+                // Bytes relayed before an error still belong in the access log.
+                (record.uploaded_bytes, record.downloaded_bytes) = traffic.totals();
+                let end = end?;
+                match end {
+                    SessionEnd::Idle(side) => info!(logger, "session idle"; "idle_side" => side),
+                    SessionEnd::Stalled(side) => {
+                        slog::warn!(logger, "session stalled"; "stalled_side" => side)
+                    }
+                    _ => {}
+                }
+                (end, traffic.totals())
+            }
+            Upstream::Udp(socket) => {
+                let Some(client_ip) = client_addr.ip() else {
+                    return Err(Error::ProtocolError("UDP ASSOCIATE needs a TCP client"));
+                };
+                let totals =
+                    socks5::relay_udp(socket, client_ip, client_reader, &self.server).await?;
+                (SessionEnd::Completed, totals)
+            }
+        };
 
+        (record.uploaded_bytes, record.downloaded_bytes) = (uploaded_bytes, downloaded_bytes);
+        record.status = Some(end.as_str());
+        self.server
+            .metrics
+            .record_traffic(uploaded_bytes, downloaded_bytes);
         let elapsed = started_at.elapsed();
-        info!(self.logger, "proxy done";
+        info!(logger, "proxy done";
             "upstream_address" => %request.address,
             "upstream_port" => request.port,
             "downloaded_bytes" => downloaded_bytes,
             "uploaded_bytes" => uploaded_bytes,
             "elapsed" => ?elapsed,
+            "end" => end.as_str(),
         );
         Ok(())
     }
 }
 
-async fn read_preamble(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<[u8; 2]> {
+async fn read_preamble(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<[u8; 2]> {
     let mut preamble = [0u8; 2];
     reader.read_exact(&mut preamble).await?;
     Ok(preamble)
 }
 
+// DeadlineReader fails reads with `DeadlinePassed` once `deadline` has passed.
+struct DeadlineReader<'a, R> {
+    inner: &'a mut R,
+    expiry: Pin<Box<tokio::time::Sleep>>,
+}
+
+// The error a `DeadlineReader` fails with.
+#[derive(Debug, thiserror::Error)]
+#[error("handshake deadline passed")]
+struct DeadlinePassed;
+
+impl<'a, R> DeadlineReader<'a, R> {
+    fn new(inner: &'a mut R, deadline: Instant) -> Self {
+        DeadlineReader {
+            inner,
+            expiry: Box::pin(tokio::time::sleep_until(deadline.into())),
+        }
+    }
+
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        match self.expiry.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, DeadlinePassed)),
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DeadlineReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_expired(cx)?;
+        Pin::new(&mut *this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for DeadlineReader<'_, R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        this.poll_expired(cx)?;
+        Pin::new(&mut *this.inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut *self.get_mut().inner).consume(amt)
+    }
+}
+
+// Traffic counts the bytes relayed in each direction and when each side last sent data.
+// Activity times are milliseconds since the session started.
+pub(crate) struct Traffic {
+    started_at: Instant,
+    // Client to upstream.
+    uploaded: AtomicU64,
+    // Upstream to client.
+    downloaded: AtomicU64,
+    client_active_at: AtomicU64,
+    upstream_active_at: AtomicU64,
+    // Pace each direction when the session has a bandwidth limit. Both are the same bucket
+    // for a combined limit.
+    upload_bucket: Option<Arc<TokenBucket>>,
+    download_bucket: Option<Arc<TokenBucket>>,
+    // Shared by all sessions of the server.
+    global_bucket: Option<Arc<TokenBucket>>,
+    write_timeout: Option<Duration>,
+}
+
+impl Traffic {
+    fn new(
+        limit: Option<&BandwidthLimit>,
+        global_bucket: Option<Arc<TokenBucket>>,
+        write_timeout: Option<Duration>,
+    ) -> Traffic {
+        let (upload_bucket, download_bucket) = match limit {
+            Some(limit) => {
+                let bucket = Arc::new(TokenBucket::new(limit.rate));
+                let download_bucket = match limit.scope {
+                    BandwidthScope::PerDirection => Arc::new(TokenBucket::new(limit.rate)),
+                    BandwidthScope::Combined => bucket.clone(),
+                };
+                (Some(bucket), Some(download_bucket))
+            }
+            None => (None, None),
+        };
+        Traffic {
+            started_at: Instant::now(),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            client_active_at: AtomicU64::new(0),
+            upstream_active_at: AtomicU64::new(0),
+            upload_bucket,
+            download_bucket,
+            global_bucket,
+            write_timeout,
+        }
+    }
+
+    // Runs one write of the relay to `side`, failing with `Stalled` if it outlasts the
+    // write timeout.
+    async fn write<T>(
+        &self,
+        side: &'static str,
+        write: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        let Some(timeout) = self.write_timeout else {
+            return write.await;
+        };
+        tokio::time::timeout(timeout, write)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, Stalled(side))))
+    }
+
+    // Waits until `n` bytes may be sent under both the session's `bucket` and the
+    // server-wide limit. Sessions queue on the shared bucket in turn, so none starves.
+    async fn pace(&self, bucket: Option<&TokenBucket>, n: usize) {
+        let session = async {
+            if let Some(bucket) = bucket {
+                bucket.take(n).await;
+            }
+        };
+        let global = async {
+            if let Some(bucket) = &self.global_bucket {
+                bucket.take(n).await;
+            }
+        };
+        tokio::join!(session, global);
+    }
+
+    // Returns (uploaded, downloaded) bytes, in the same order as `socks5::relay_udp`.
+    pub(crate) fn totals(&self) -> (u64, u64) {
+        (
+            self.uploaded.load(Ordering::Relaxed),
+            self.downloaded.load(Ordering::Relaxed),
+        )
+    }
+
+    fn now(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+
+    // Completes once neither side has sent data for `timeout`, returning the side that has
+    // been silent the longest.
+    async fn wait_idle(&self, timeout: Duration) -> &'static str {
+        loop {
+            let client = self.client_active_at.load(Ordering::Relaxed);
+            let upstream = self.upstream_active_at.load(Ordering::Relaxed);
+            let deadline = Duration::from_millis(client.max(upstream)) + timeout;
+            let now = Duration::from_millis(self.now());
+            if deadline <= now {
+                return if client <= upstream {
+                    "client"
+                } else {
+                    "upstream"
+                };
+            }
+            tokio::time::sleep(deadline - now).await;
+        }
+    }
+}
+
+// Runs `proxy`, logging the bytes relayed so far every `interval` until it completes.
+async fn with_progress<T>(
+    proxy: impl Future<Output = T>,
+    traffic: &Traffic,
+    interval: Option<Duration>,
+    logger: &slog::Logger,
+) -> T {
+    let Some(interval) = interval else {
+        return proxy.await;
+    };
+    tokio::pin!(proxy);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            result = &mut proxy => return result,
+            _ = ticker.tick() => {
+                let (uploaded_bytes, downloaded_bytes) = traffic.totals();
+                info!(logger, "proxy progress";
+                    "downloaded_bytes" => downloaded_bytes,
+                    "uploaded_bytes" => uploaded_bytes,
+                    "elapsed" => ?traffic.started_at.elapsed(),
+                );
+            }
+        }
+    }
+}
+
+// How a proxied TCP session ended.
+enum SessionEnd {
+    // One side closed the connection.
+    Completed,
+    // Neither side sent data for the idle timeout; holds the side that went quiet first.
+    Idle(&'static str),
+    // The session reached the maximum session duration.
+    Capped,
+    // A write blocked for the write timeout; holds the side that stopped reading.
+    Stalled(&'static str),
+}
+
+// The error that ends a relay whose write to the held side timed out.
+#[derive(Debug, thiserror::Error)]
+#[error("write to {0} stalled")]
+struct Stalled(&'static str);
+
+impl SessionEnd {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SessionEnd::Completed => "completed",
+            SessionEnd::Idle(_) => "idle",
+            SessionEnd::Capped => "capped",
+            SessionEnd::Stalled(_) => "stalled",
+        }
+    }
+}
+
 async fn do_proxy(
     client_reader: impl AsyncBufRead + Unpin,
     client_writer: impl AsyncWrite + Unpin,
     upstream_reader: impl AsyncBufRead + Unpin,
     upstream_writer: impl AsyncWrite + Unpin,
-) -> io::Result<(u64, u64)> {
-    tokio::try_join!(
-        copy_and_drop(client_reader, upstream_writer),
-        copy_and_drop(upstream_reader, client_writer),
-    )
+    traffic: &Traffic,
+    idle_timeout: Option<Duration>,
+) -> io::Result<SessionEnd> {
+    let relay = async {
+        tokio::try_join!(
+            copy_and_drop(
+                client_reader,
+                upstream_writer,
+                "upstream",
+                &traffic.uploaded,
+                &traffic.client_active_at,
+                traffic.upload_bucket.as_deref(),
+                traffic,
+            ),
+            copy_and_drop(
+                upstream_reader,
+                client_writer,
+                "client",
+                &traffic.downloaded,
+                &traffic.upstream_active_at,
+                traffic.download_bucket.as_deref(),
+                traffic,
+            ),
+        )
+    };
+    run_relay(relay, traffic, idle_timeout).await
+}
+
+// Relays like `do_proxy`, but with splice(2) so that the data never enters userspace.
+#[cfg(target_os = "linux")]
+async fn do_splice_proxy(
+    client: &tokio::net::TcpStream,
+    upstream: &tokio::net::TcpStream,
+    traffic: &Traffic,
+    idle_timeout: Option<Duration>,
+) -> io::Result<SessionEnd> {
+    let relay = async {
+        tokio::try_join!(
+            splice_and_drop(
+                client,
+                upstream,
+                "upstream",
+                &traffic.uploaded,
+                &traffic.client_active_at,
+                traffic.upload_bucket.as_deref(),
+                traffic,
+            ),
+            splice_and_drop(
+                upstream,
+                client,
+                "client",
+                &traffic.downloaded,
+                &traffic.upstream_active_at,
+                traffic.download_bucket.as_deref(),
+                traffic,
+            ),
+        )
+    };
+    run_relay(relay, traffic, idle_timeout).await
+}
+
+// Waits for both directions of a relay, or for the session to go idle.
+async fn run_relay(
+    relay: impl Future<Output = io::Result<((), ())>>,
+    traffic: &Traffic,
+    idle_timeout: Option<Duration>,
+) -> io::Result<SessionEnd> {
+    let result = match idle_timeout {
+        Some(idle_timeout) => tokio::select! {
+            result = relay => result,
+            side = traffic.wait_idle(idle_timeout) => return Ok(SessionEnd::Idle(side)),
+        },
+        None => relay.await,
+    };
+    match result {
+        Ok(_) => Ok(SessionEnd::Completed),
+        Err(e) => match e.get_ref().and_then(|e| e.downcast_ref::<Stalled>()) {
+            Some(Stalled(side)) => Ok(SessionEnd::Stalled(side)),
+            None => Err(e),
+        },
+    }
 }
 
+// Copies until EOF and then shuts down the writer, which is the `side` of the session.
+// Counts bytes into `transferred`, stamps `active_at` on every read and waits for bandwidth
+// tokens before each write.
 async fn copy_and_drop(
     mut reader: impl AsyncBufRead + Unpin,
     mut writer: impl AsyncWrite + Unpin,
-) -> io::Result<u64> {
-    let n = tokio::io::copy_buf(&mut reader, &mut writer).await?;
+    side: &'static str,
+    transferred: &AtomicU64,
+    active_at: &AtomicU64,
+    bucket: Option<&TokenBucket>,
+    traffic: &Traffic,
+) -> io::Result<()> {
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        let n = buf.len();
+        active_at.store(traffic.now(), Ordering::Relaxed);
+        traffic.pace(bucket, n).await;
+        traffic.write(side, writer.write_all(buf)).await?;
+        reader.consume(n);
+        transferred.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    // Half-close so the peer sees EOF while the other direction keeps flowing.
+    writer.shutdown().await?;
     drop(writer);
     drop(reader);
-    Ok(n)
+    Ok(())
+}
+
+// The splice(2) counterpart of `copy_and_drop`. Each chunk is moved from `reader` into a
+// pipe and then drained from the pipe into `writer` before the next read.
+#[cfg(target_os = "linux")]
+async fn splice_and_drop(
+    reader: &tokio::net::TcpStream,
+    writer: &tokio::net::TcpStream,
+    side: &'static str,
+    transferred: &AtomicU64,
+    active_at: &AtomicU64,
+    bucket: Option<&TokenBucket>,
+    traffic: &Traffic,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let pipe = splice::Pipe::new()?;
+    loop {
+        let n = loop {
+            reader.readable().await?;
+            let result = reader.try_io(Interest::READABLE, || {
+                splice::splice(
+                    reader.as_raw_fd(),
+                    pipe.write.as_raw_fd(),
+                    splice::PIPE_SIZE,
+                )
+            });
+            match result {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        active_at.store(traffic.now(), Ordering::Relaxed);
+        traffic.pace(bucket, n).await;
+        let drain = async {
+            let mut remaining = n;
+            while remaining > 0 {
+                writer.writable().await?;
+                let result = writer.try_io(Interest::WRITABLE, || {
+                    splice::splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), remaining)
+                });
+                match result {
+                    Ok(written) => remaining -= written,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        };
+        traffic.write(side, drain).await?;
+        transferred.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    // Half-close so the peer sees EOF while the other direction keeps flowing.
+    socket2::SockRef::from(writer).shutdown(std::net::Shutdown::Write)?;
+    Ok(())
 }
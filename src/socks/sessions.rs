@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use slog::info;
+use tokio::sync::Notify;
+
+use crate::socks::server::{ConnId, Traffic};
+use crate::socks::{ClientAddr, Destination};
+
+// SessionInfo is what the registry knows about a live session. The fields after
+// `started_at` fill in as the handshake progresses.
+#[derive(Clone)]
+pub(crate) struct SessionInfo {
+    pub client_addr: ClientAddr,
+    pub started_at: Instant,
+    pub protocol: Option<&'static str>,
+    pub user: Option<String>,
+    pub destination: Option<Destination>,
+    // The counters of a TCP relay, read when listing so that the bytes are current. UDP
+    // associations count their datagrams privately and are listed without bytes.
+    pub traffic: Option<Arc<Traffic>>,
+    // Notified to close the session.
+    #[cfg(feature = "admin")]
+    pub cancel: Arc<Notify>,
+}
+
+// Sessions lists the sessions `Server::serve` is handling, so that SIGUSR2 and the admin
+// API can show what the proxy is doing right now. Sessions run through `proxy_connection`
+// have no ID and are not listed.
+#[derive(Default)]
+pub(crate) struct Sessions {
+    sessions: Mutex<HashMap<ConnId, SessionInfo>>,
+}
+
+impl Sessions {
+    // Registers a session, returning what is notified when it is to be closed.
+    pub fn insert(&self, id: ConnId, client_addr: ClientAddr) -> Arc<Notify> {
+        let cancel = Arc::new(Notify::new());
+        let info = SessionInfo {
+            client_addr,
+            started_at: Instant::now(),
+            protocol: None,
+            user: None,
+            destination: None,
+            traffic: None,
+            #[cfg(feature = "admin")]
+            cancel: cancel.clone(),
+        };
+        self.sessions.lock().unwrap().insert(id, info);
+        cancel
+    }
+
+    pub fn update(&self, id: ConnId, update: impl FnOnce(&mut SessionInfo)) {
+        if let Some(info) = self.sessions.lock().unwrap().get_mut(&id) {
+            update(info);
+        }
+    }
+
+    pub fn remove(&self, id: ConnId) -> Option<SessionInfo> {
+        self.sessions.lock().unwrap().remove(&id)
+    }
+
+    // Closes the session `id`, returning false if there is no such session.
+    #[cfg(feature = "admin")]
+    pub fn cancel(&self, id: ConnId) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            // Stored if the session is not waiting yet, so the cancellation is never lost.
+            Some(info) => {
+                info.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the sessions, oldest first. They are copied out so that sessions starting
+    // and ending do not wait for the caller.
+    pub fn snapshot(&self) -> Vec<(ConnId, SessionInfo)> {
+        let mut sessions: Vec<_> = (self.sessions.lock().unwrap())
+            .iter()
+            .map(|(id, info)| (*id, info.clone()))
+            .collect();
+        sessions.sort_by_key(|(_, info)| info.started_at);
+        sessions
+    }
+
+    // Logs one line per session, oldest first.
+    pub fn dump(&self, logger: &slog::Logger) {
+        let sessions = self.snapshot();
+        info!(logger, "active sessions"; "active" => sessions.len());
+        for (id, info) in sessions {
+            let totals = info.traffic.as_ref().map(|traffic| traffic.totals());
+            info!(logger, "session";
+                "id" => id,
+                "client_addr" => %info.client_addr,
+                "protocol" => info.protocol,
+                "user" => info.user,
+                "destination" => info.destination.map(|d| d.to_string()),
+                "uploaded_bytes" => totals.map(|(uploaded, _)| uploaded),
+                "downloaded_bytes" => totals.map(|(_, downloaded)| downloaded),
+                "age" => ?info.started_at.elapsed(),
+            );
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use std::io;
+use std::net::{IpAddr, Ipv4Addr};
 
 use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
 
 use crate::socks::*;
 
+// Longest ident or SOCKS4a domain name accepted, excluding the NUL terminator.
+const MAX_STRING_LEN: usize = 255;
+
 #[repr(u8)]
 enum Status {
     Granted = 0x5a,
@@ -15,36 +18,87 @@ pub async fn handshake(
     reader: &mut (impl AsyncBufRead + Unpin),
     writer: &mut (impl AsyncWrite + Unpin),
     cmd: u8,
-) -> Result<(Request, TcpStream)> {
-    let request = read_request(reader, cmd).await?;
+    server: &Server,
+) -> Result<(Request, Upstream)> {
+    do_handshake(reader, writer, cmd, None, server, &mut None).await
+}
+
+// Like `handshake`, but also passes the client's IP address to the ident authorizer and
+// reports the requested destination in `dst`.
+pub(crate) async fn do_handshake(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    cmd: u8,
+    client_ip: Option<IpAddr>,
+    server: &Server,
+    dst: &mut Option<Destination>,
+) -> Result<(Request, Upstream)> {
+    let mut request = match read_request(reader, cmd).await {
+        Ok(request) => request,
+        // A malformed request, such as a SOCKS4a one with an empty domain, is answered
+        // before hanging up. A broken connection is not.
+        Err(e @ Error::ProtocolError(_)) => {
+            write_response(writer, Status::RejectedOrFailed).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    *dst = Some(Destination::new(&request.address, request.port));
+    if !server.acl().port_policy.is_allowed(request.port) {
+        write_response(writer, Status::RejectedOrFailed).await?;
+        return Err(Error::PortNotAllowed(
+            request.address.to_string(),
+            request.port,
+        ));
+    }
     if request.command != COMMAND_CONNECT {
         write_response(writer, Status::RejectedOrFailed).await?;
-        return Err(Error::ProtocolError("command not supported"));
+        return Err(Error::UnsupportedCommand(request.command));
     }
-    let upstream = match connect_to_upstream(&request.address, request.port).await {
-        Ok(upstream) => upstream,
-        Err(e) => {
+    if let Some(authorizer) = &server.ident_authorizer {
+        let ident = request.ident.as_deref();
+        if let AuthResult::Deny = authorizer.authorize(ident, client_ip).await {
             write_response(writer, Status::RejectedOrFailed).await?;
-            return Err(Error::IoError(e));
+            return Err(Error::IdentRejected(request.ident));
         }
+    }
+    let (upstream, upstream_addr) =
+        match connect_to_upstream(&request.address, request.port, server).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                // SOCKS4 has a single failure reply, so hiding errors only adds the delay.
+                let status = failure_reply(
+                    Status::RejectedOrFailed,
+                    Status::RejectedOrFailed,
+                    server,
+                    client_ip,
+                )
+                .await;
+                write_response(writer, status).await?;
+                return Err(e);
+            }
+        };
+    request.upstream_addr = upstream_addr;
+    let ip = match request.address {
+        Address::IPv4(ip) => Ipv4Addr::from(ip),
+        // SOCKS4a has no way to echo a domain.
+        _ => Ipv4Addr::UNSPECIFIED,
     };
-    write_response(writer, Status::Granted).await?;
-    Ok((request, upstream))
+    write_reply(writer, Status::Granted, ip, request.port).await?;
+    Ok((request, Upstream::Tcp(upstream)))
 }
 
-async fn read_request(reader: &mut (impl AsyncBufRead + Unpin), cmd: u8) -> io::Result<Request> {
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin), cmd: u8) -> Result<Request> {
     let dst_port = reader.read_u16().await?;
 
     let mut dst_addr = [0u8; 4];
     reader.read_exact(&mut dst_addr).await?;
 
-    let mut ident = Vec::new();
-    reader.read_until(0, &mut ident).await?;
+    let ident = read_nul_terminated(reader, "ident is too long").await?;
 
     let dst_addr = if is_socks4a(dst_addr) {
-        let mut domain = Vec::new();
-        reader.read_until(0, &mut domain).await?;
-        domain.pop(); // remove NUL
+        let domain = read_nul_terminated(reader, "domain name is too long").await?;
+        validate_domain(&domain)?;
         Address::Domain(domain.into())
     } else {
         Address::IPv4(dst_addr)
@@ -54,16 +108,51 @@ async fn read_request(reader: &mut (impl AsyncBufRead + Unpin), cmd: u8) -> io::
         command: cmd,
         address: dst_addr,
         port: dst_port,
+        user: None,
+        upstream_addr: None,
+        ident: (!ident.is_empty()).then(|| String::from_utf8_lossy(&ident).into_owned()),
     })
 }
 
+// Reads a NUL-terminated string of at most MAX_STRING_LEN bytes, without the NUL.
+// A client that never sends the NUL must not make us buffer without bound.
+async fn read_nul_terminated(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    too_long: &'static str,
+) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let limit = MAX_STRING_LEN as u64 + 1;
+    (&mut *reader).take(limit).read_until(0, &mut buf).await?;
+    if buf.last() == Some(&0) {
+        buf.pop();
+        return Ok(buf);
+    }
+    if buf.len() > MAX_STRING_LEN {
+        return Err(Error::ProtocolError(too_long));
+    }
+    Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+}
+
+// Writes a reply with the DSTPORT and DSTIP fields zeroed, as failures are answered.
 async fn write_response(writer: &mut (impl AsyncWrite + Unpin), status: Status) -> io::Result<()> {
+    write_reply(writer, status, Ipv4Addr::UNSPECIFIED, 0).await
+}
+
+// Writes a reply echoing the requested destination, which some strict clients check.
+async fn write_reply(
+    writer: &mut (impl AsyncWrite + Unpin),
+    status: Status,
+    ip: Ipv4Addr,
+    port: u16,
+) -> io::Result<()> {
+    let [port_hi, port_lo] = port.to_be_bytes();
+    let [a, b, c, d] = ip.octets();
     #[rustfmt::skip]
     writer.write_all(&[
-        0,            // VN
-        status as u8, // REP
-        0, 0,         // DSTPORT
-        0, 0, 0, 0,   // DSTIP
+        0,                // VN
+        status as u8,     // REP
+        port_hi, port_lo, // DSTPORT
+        a, b, c, d,       // DSTIP
     ]).await?;
     Ok(())
 }
@@ -1,6 +1,11 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::task::Poll;
+use std::time::Duration;
+
 use smallvec::smallvec;
-use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 use crate::socks::*;
 
@@ -8,29 +13,23 @@ use crate::socks::*;
 enum Status {
     Granted = 0x00,
     GeneralFailure = 0x01,
+    // The destination is refused by policy: the port policy, the domain denylist or
+    // `block_private_destinations`. Unless errors are hidden from the client, these never
+    // get a network failure code.
+    ConnectionNotAllowed = 0x02,
     NetworkUnreachable = 0x03,
     HostUnreachable = 0x04,
     ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
     CommandNotSupported = 0x07,
     AddressTypeNotSupported = 0x08,
 }
 
-pub enum Auth<'a> {
-    None,
-    UsernamePassword {
-        username: &'a [u8],
-        password: &'a [u8],
-    },
-}
-
-pub enum AuthResult {
-    Accept,
-    Deny,
-}
-
 #[repr(u8)]
 enum AuthMethod {
     None = 0x00,
+    // GSSAPI is recognized but not supported; it is skipped in favor of other methods.
+    Gssapi = 0x01,
     UsernamePassword = 0x02,
     NoAcceptableMethods = 0xff,
 }
@@ -41,75 +40,419 @@ enum AuthStatus {
     Failure = 0xff,
 }
 
+// Address sent in error replies, where no connection exists to report.
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
 pub async fn handshake(
     reader: &mut (impl AsyncBufRead + Unpin),
     writer: &mut (impl AsyncWrite + Unpin),
     n_auth: u8,
-) -> Result<(Request, TcpStream)> {
-    authenticate_client(reader, writer, n_auth).await?;
-    let request = read_request(reader, writer).await?;
-    if request.command != COMMAND_CONNECT {
-        write_response(writer, Status::CommandNotSupported).await?;
-        return Err(Error::ProtocolError("command not supported"));
-    }
-    let upstream = match connect_to_upstream(&request.address, request.port).await {
-        Ok(upstream) => upstream,
+    server: &Server,
+) -> Result<(Request, Upstream)> {
+    do_handshake(reader, writer, n_auth, None, None, server, &mut None).await
+}
+
+// Like `handshake`, but also reports the requested destination in `dst`. Failure replies
+// to `client_ip` are generic if the server hides errors from it. BIND and UDP ASSOCIATE
+// listen on `local_ip`, the address the client reached the server on, when it is known.
+pub(crate) async fn do_handshake(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    n_auth: u8,
+    client_ip: Option<IpAddr>,
+    local_ip: Option<IpAddr>,
+    server: &Server,
+    dst: &mut Option<Destination>,
+) -> Result<(Request, Upstream)> {
+    let auth = authenticate_client(reader, writer, n_auth, server);
+    let user = match tokio::time::timeout(server.auth_timeout, auth).await {
+        Ok(result) => result?,
+        Err(_) => return Err(Error::AuthTimeout),
+    };
+    let mut request = read_request(reader, writer, server.address_family).await?;
+    request.user = user;
+    *dst = Some(Destination::new(&request.address, request.port));
+    if request.command != COMMAND_UDP_ASSOCIATE
+        && !server.acl().port_policy.is_allowed(request.port)
+    {
+        let status = failure_reply(
+            Status::ConnectionNotAllowed,
+            Status::GeneralFailure,
+            server,
+            client_ip,
+        )
+        .await;
+        write_response(writer, status, UNSPECIFIED_ADDR).await?;
+        return Err(Error::PortNotAllowed(
+            request.address.to_string(),
+            request.port,
+        ));
+    }
+    let upstream = match request.command {
+        // BIND and UDP ASSOCIATE cannot be passed through the upstream proxy, and serving
+        // them directly would bypass it.
+        COMMAND_BIND | COMMAND_UDP_ASSOCIATE if server.upstream_proxy.is_some() => {
+            write_response(writer, Status::CommandNotSupported, UNSPECIFIED_ADDR).await?;
+            return Err(Error::UnsupportedCommand(request.command));
+        }
+        COMMAND_CONNECT => {
+            let (upstream, upstream_addr) =
+                handle_connect(writer, &request, client_ip, server).await?;
+            request.upstream_addr = upstream_addr;
+            Upstream::Tcp(upstream)
+        }
+        COMMAND_BIND => {
+            let inbound = handle_bind(writer, &request, local_ip, server.bind_timeout).await?;
+            Upstream::Tcp(inbound)
+        }
+        COMMAND_UDP_ASSOCIATE => {
+            let socket = handle_udp_associate(writer, local_ip, client_ip).await?;
+            Upstream::Udp(socket)
+        }
+        _ => {
+            write_response(writer, Status::CommandNotSupported, UNSPECIFIED_ADDR).await?;
+            return Err(Error::UnsupportedCommand(request.command));
+        }
+    };
+    Ok((request, upstream))
+}
+
+async fn handle_connect(
+    writer: &mut (impl AsyncWrite + Unpin),
+    request: &Request,
+    client_ip: Option<IpAddr>,
+    server: &Server,
+) -> Result<(TcpStream, Option<SocketAddr>)> {
+    let (upstream, upstream_addr) =
+        match connect_to_upstream(&request.address, request.port, server).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                let status = match &e {
+                    Error::UpstreamConnect(e) => io_error_to_status(e),
+                    Error::UpstreamProxyRefused(reply) => reply_to_status(*reply),
+                    Error::DestinationBlocked(_) | Error::DomainDenied(_) => {
+                        Status::ConnectionNotAllowed
+                    }
+                    Error::ResolveFailed(..) => Status::HostUnreachable,
+                    // Local failures, such as a source address that cannot be bound, say
+                    // nothing about the destination.
+                    _ => Status::GeneralFailure,
+                };
+                let status = failure_reply(status, Status::GeneralFailure, server, client_ip).await;
+                write_response(writer, status, UNSPECIFIED_ADDR).await?;
+                return Err(e);
+            }
+        };
+    write_response(writer, Status::Granted, upstream.local_addr()?).await?;
+    Ok((upstream, upstream_addr))
+}
+
+// BIND sends two replies: the first one tells the client where the server is listening,
+// and the second one is sent when the inbound connection arrives.
+async fn handle_bind(
+    writer: &mut (impl AsyncWrite + Unpin),
+    request: &Request,
+    local_ip: Option<IpAddr>,
+    timeout: Duration,
+) -> Result<TcpStream> {
+    // The reply tells the client where the peer should connect, so listen on the address
+    // the client itself reached. A peer of the other family could not connect there, and
+    // gets the unspecified address of its own family instead.
+    let bind_ip = match (local_ip.map(|ip| ip.to_canonical()), &request.address) {
+        (Some(ip @ IpAddr::V4(_)), Address::IPv4(_) | Address::Domain(_))
+        | (Some(ip @ IpAddr::V6(_)), Address::IPv6(_) | Address::Domain(_)) => ip,
+        (_, Address::IPv6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let listener = match TcpListener::bind((bind_ip, 0)).await {
+        Ok(listener) => listener,
         Err(e) => {
-            write_response(writer, io_error_to_status(&e)).await?;
+            write_response(writer, Status::GeneralFailure, UNSPECIFIED_ADDR).await?;
             return Err(Error::IoError(e));
         }
     };
-    write_response(writer, Status::Granted).await?;
-    Ok((request, upstream))
+    let local_addr = listener.local_addr()?;
+    write_response(writer, Status::Granted, local_addr).await?;
+
+    // The listener is closed on return, so an abandoned BIND holds its port no longer
+    // than `timeout`.
+    let (inbound, peer_addr) = match tokio::time::timeout(timeout, listener.accept()).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            write_response(writer, Status::GeneralFailure, UNSPECIFIED_ADDR).await?;
+            return Err(Error::IoError(e));
+        }
+        Err(_) => {
+            write_response(writer, Status::GeneralFailure, UNSPECIFIED_ADDR).await?;
+            return Err(Error::BindTimeout(local_addr));
+        }
+    };
+    write_response(writer, Status::Granted, peer_addr).await?;
+    Ok(inbound)
+}
+
+async fn handle_udp_associate(
+    writer: &mut (impl AsyncWrite + Unpin),
+    local_ip: Option<IpAddr>,
+    client_ip: Option<IpAddr>,
+) -> Result<UdpSocket> {
+    // The client sends its datagrams to the address in the reply, so listen where it
+    // reached the server, which is also in its family.
+    let bind_ip = match (local_ip, client_ip) {
+        (Some(ip), _) => ip.to_canonical(),
+        (None, Some(ip)) if ip.to_canonical().is_ipv6() => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        (None, _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let socket = match UdpSocket::bind((bind_ip, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            write_response(writer, Status::GeneralFailure, UNSPECIFIED_ADDR).await?;
+            return Err(Error::IoError(e));
+        }
+    };
+    write_response(writer, Status::Granted, socket.local_addr()?).await?;
+    Ok(socket)
+}
+
+// Relays datagrams between the client and upstream hosts until the controlling TCP
+// connection is closed. Returns (uploaded bytes, downloaded bytes) of payload.
+pub async fn relay_udp(
+    client_socket: UdpSocket,
+    client_ip: IpAddr,
+    mut control: impl AsyncBufRead + Unpin,
+    server: &Server,
+) -> Result<(u64, u64)> {
+    let mut upstream = UdpUpstream::default();
+
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut uploaded_bytes = 0u64;
+    let mut downloaded_bytes = 0u64;
+    let mut control_buf = [0u8; 64];
+    let mut client_buf = vec![0u8; 65536];
+    let mut upstream_buf = vec![0u8; 65536];
+    loop {
+        tokio::select! {
+            n = control.read(&mut control_buf) => {
+                // The association ends when the client closes the TCP connection.
+                if n? == 0 {
+                    break;
+                }
+            }
+            r = client_socket.recv_from(&mut client_buf) => {
+                let (n, from) = r?;
+                // A dual-stack socket reports IPv4 clients as IPv4-mapped addresses.
+                if from.ip().to_canonical() != client_ip.to_canonical() {
+                    continue;
+                }
+                client_addr = Some(from);
+                let acl = server.acl();
+                let Some((address, port, payload)) = parse_udp_header(&client_buf[..n]) else {
+                    continue;
+                };
+                if let Address::Domain(domain) = &address {
+                    // Resolving the domain here would leak it to the local resolver.
+                    if server.remote_dns || acl.denied_domains.matches(domain) {
+                        continue;
+                    }
+                }
+                let Ok(mut addrs) = resolve_address(&address, port, server.resolver.as_ref()).await
+                else {
+                    continue;
+                };
+                // Like a connection, a datagram only goes to an address of the allowed family.
+                if server.address_family.apply(&mut addrs).is_err() {
+                    continue;
+                }
+                let dst = addrs[0];
+                if !acl.port_policy.is_allowed(port)
+                    || acl.block_private_destinations && is_private_destination(dst.ip())
+                {
+                    continue;
+                }
+                let upstream_socket = match upstream.socket_for(dst, server) {
+                    Ok(socket) => socket,
+                    // Like a TCP connection, the association fails if it cannot be bound
+                    // as configured. Other errors, such as a host without IPv6, only cost
+                    // the datagram.
+                    Err(e @ (Error::SourceBindFailed(..) | Error::InterfaceBindFailed(..))) => {
+                        return Err(e)
+                    }
+                    Err(_) => continue,
+                };
+                if upstream_socket.send_to(payload, dst).await.is_ok() {
+                    uploaded_bytes += payload.len() as u64;
+                }
+            }
+            r = upstream.recv_from(&mut upstream_buf) => {
+                let (n, from) = r?;
+                let Some(client_addr) = client_addr else {
+                    continue;
+                };
+                let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+                let mut datagram = vec![0x00, 0x00, 0x00]; // RSV, FRAG
+                encode_socket_addr(&mut datagram, from);
+                datagram.extend_from_slice(&upstream_buf[..n]);
+                if client_socket.send_to(&datagram, client_addr).await.is_ok() {
+                    downloaded_bytes += n as u64;
+                }
+            }
+        }
+    }
+    Ok((uploaded_bytes, downloaded_bytes))
+}
+
+// UdpUpstream holds the sockets datagrams are relayed upstream from, one per family. Each
+// is opened on first use, so that it can be bound to the source address of its family.
+#[derive(Default)]
+struct UdpUpstream {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl UdpUpstream {
+    fn socket_for(&mut self, dst: SocketAddr, server: &Server) -> Result<&UdpSocket> {
+        let socket = match dst {
+            SocketAddr::V4(_) => &mut self.v4,
+            SocketAddr::V6(_) => &mut self.v6,
+        };
+        if socket.is_none() {
+            *socket = Some(bind_udp_socket(dst, server)?);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+
+    // Receives a datagram from whichever socket has one. Never completes while no socket
+    // is open.
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            for socket in self.v4.iter().chain(&self.v6) {
+                let mut read = ReadBuf::new(buf);
+                if let Poll::Ready(from) = socket.poll_recv_from(cx, &mut read) {
+                    return Poll::Ready(from.map(|from| (read.filled().len(), from)));
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+// Parses the header of a UDP request datagram and returns the destination and payload.
+// Returns None for malformed datagrams and for fragments, which are not supported.
+fn parse_udp_header(buf: &[u8]) -> Option<(Address, u16, &[u8])> {
+    let [_rsv1, _rsv2, frag, atyp, rest @ ..] = buf else {
+        return None;
+    };
+    if *frag != 0 {
+        return None;
+    }
+    let (address, rest) = match atyp {
+        0x01 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (Address::IPv4(*ip), rest)
+        }
+        0x04 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (Address::IPv6(*ip), rest)
+        }
+        0x03 => {
+            let (len, rest) = rest.split_first()?;
+            if rest.len() < *len as usize {
+                return None;
+            }
+            let (domain, rest) = rest.split_at(*len as usize);
+            (Address::Domain(domain.into()), rest)
+        }
+        _ => return None,
+    };
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    Some((address, u16::from_be_bytes(*port), payload))
 }
 
 async fn authenticate_client(
     reader: &mut (impl AsyncBufRead + Unpin),
     writer: &mut (impl AsyncWrite + Unpin),
     n_auth: u8,
-) -> Result<()> {
-    let methods = read_available_methods(reader, n_auth).await?;
+    server: &Server,
+) -> Result<Option<String>> {
+    let authenticator = server.authenticator.as_ref();
+    if n_auth == 0 {
+        write_server_choice(writer, AuthMethod::NoAcceptableMethods).await?;
+        return Err(Error::ProtocolError("no auth methods offered"));
+    }
+    let offered = read_available_methods(reader, n_auth).await?;
+    let (offers_none, offers_password) = (offered.none, offered.password);
+
+    let try_none_first = match server.auth_policy {
+        AuthPolicy::RequireAuth => false,
+        AuthPolicy::PreferAuth => !offers_password,
+        AuthPolicy::PreferAnonymous => true,
+    };
+    if offers_none && try_none_first {
+        if let AuthResult::Accept = authenticator.authenticate(Auth::None).await {
+            write_server_choice(writer, AuthMethod::None).await?;
+            return Ok(None);
+        }
+    }
 
-    if methods.contains(&(AuthMethod::UsernamePassword as u8)) {
+    if offers_password {
         write_server_choice(writer, AuthMethod::UsernamePassword).await?;
-        let (username, password) = read_username_and_password(reader).await?;
-        match do_authenticate(Auth::UsernamePassword {
+        let (username, password) = match read_username_and_password(reader).await {
+            Ok(credentials) => credentials,
+            Err(e @ Error::ProtocolError(_)) => {
+                write_auth_response(writer, AuthStatus::Failure).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let auth = Auth::UsernamePassword {
             username: &username,
             password: &password,
-        }) {
+        };
+        match authenticator.authenticate(auth).await {
             AuthResult::Accept => {}
             AuthResult::Deny => {
                 write_auth_response(writer, AuthStatus::Failure).await?;
-                return Err(Error::ProtocolError("authentication failure"));
+                return Err(Error::AuthFailure);
             }
         }
         write_auth_response(writer, AuthStatus::Success).await?;
-        return Ok(());
-    }
-
-    if methods.contains(&(AuthMethod::None as u8)) {
-        match do_authenticate(Auth::None) {
-            AuthResult::Accept => {}
-            AuthResult::Deny => {
-                write_server_choice(writer, AuthMethod::NoAcceptableMethods).await?;
-                return Err(Error::ProtocolError("no acceptable auth methods"));
-            }
-        }
-        write_server_choice(writer, AuthMethod::None).await?;
-        return Ok(());
+        return Ok(Some(String::from_utf8_lossy(&username).into_owned()));
     }
 
     write_server_choice(writer, AuthMethod::NoAcceptableMethods).await?;
+    if offered.gssapi && !offers_none {
+        return Err(Error::ProtocolError(
+            "GSSAPI authentication is not supported and no other method was offered",
+        ));
+    }
     Err(Error::ProtocolError("no acceptable auth methods"))
 }
 
+// The methods a client offered, out of those the server knows about.
+#[derive(Default)]
+struct OfferedMethods {
+    none: bool,
+    password: bool,
+    gssapi: bool,
+}
+
+// Reads the method list in one pass, keeping only what selection needs. The reader is
+// buffered, so reading byte by byte costs no syscalls.
 async fn read_available_methods(
     reader: &mut (impl AsyncBufRead + Unpin),
     n_auth: u8,
-) -> io::Result<ByteBuf> {
-    let mut buf = smallvec![0u8; n_auth as usize];
-    reader.read_exact(&mut buf).await?;
-    Ok(buf)
+) -> io::Result<OfferedMethods> {
+    let mut offered = OfferedMethods::default();
+    for _ in 0..n_auth {
+        match reader.read_u8().await? {
+            m if m == AuthMethod::None as u8 => offered.none = true,
+            m if m == AuthMethod::UsernamePassword as u8 => offered.password = true,
+            m if m == AuthMethod::Gssapi as u8 => offered.gssapi = true,
+            _ => {}
+        }
+    }
+    Ok(offered)
 }
 
 async fn write_server_choice(
@@ -120,11 +463,18 @@ async fn write_server_choice(
     Ok(())
 }
 
+// Reads the username/password request of RFC 1929. Both fields are read in full or not
+// at all, so a short read surfaces as an error instead of leaving the stream misaligned.
 async fn read_username_and_password(
     reader: &mut (impl AsyncBufRead + Unpin),
-) -> io::Result<(ByteBuf, ByteBuf)> {
-    // read auth version
-    let _ = reader.read_u8().await?;
+) -> Result<(ByteBuf, ByteBuf)> {
+    // read auth version; anything else means the client is not speaking RFC 1929 and the
+    // rest of the stream cannot be trusted to line up
+    if reader.read_u8().await? != 0x01 {
+        return Err(Error::ProtocolError(
+            "unsupported auth sub-negotiation version",
+        ));
+    }
 
     // read username
     let username_len = reader.read_u8().await?;
@@ -150,12 +500,18 @@ async fn write_auth_response(
 async fn read_request(
     reader: &mut (impl AsyncBufRead + Unpin),
     writer: &mut (impl AsyncWrite + Unpin),
+    family: AddressFamily,
 ) -> Result<Request> {
     let mut addr_buf = [0u8; 4];
     reader.read_exact(&mut addr_buf).await?;
     if addr_buf[0] != 0x05 {
         return Err(Error::ProtocolError("request is not SOCKS5"));
     }
+    // RFC 1928 requires RSV to be zero.
+    if addr_buf[2] != 0x00 {
+        write_response(writer, Status::GeneralFailure, UNSPECIFIED_ADDR).await?;
+        return Err(Error::ProtocolError("reserved byte is not zero"));
+    }
     let command = addr_buf[1];
     let address = match addr_buf[3] {
         0x01 => {
@@ -175,50 +531,84 @@ async fn read_request(
             let len = reader.read_u8().await?;
             let mut buf: ByteBuf = smallvec![0u8; len as usize];
             reader.read_exact(&mut buf).await?;
+            if let Err(e) = validate_domain(&buf) {
+                write_response(writer, Status::AddressTypeNotSupported, UNSPECIFIED_ADDR).await?;
+                return Err(e);
+            }
             Address::Domain(buf)
         }
         _ => {
-            write_response(writer, Status::AddressTypeNotSupported).await?;
+            write_response(writer, Status::AddressTypeNotSupported, UNSPECIFIED_ADDR).await?;
             return Err(Error::ProtocolError("unknown address type"));
         }
     };
     let port = reader.read_u16().await?;
+    // Fail fast instead of letting the connector find no usable address.
+    if !family.allows(&address) {
+        write_response(writer, Status::AddressTypeNotSupported, UNSPECIFIED_ADDR).await?;
+        return Err(Error::ProtocolError("address family is disabled"));
+    }
     Ok(Request {
         command,
         address,
         port,
+        user: None,
+        upstream_addr: None,
+        ident: None,
     })
 }
 
-async fn write_response(writer: &mut (impl AsyncWrite + Unpin), status: Status) -> io::Result<()> {
-    #[rustfmt::skip]
-    writer.write_all(&[
-        0x05,                   // version
-        status as u8,           // status
-        0x00,                   // reserved
-        0x01,                   // address type
-        0x00, 0x00, 0x00, 0x00, // IPv4 address
-        0x00, 0x00,             // port
-    ]).await?;
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    status: Status,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    let mut buf: ByteBuf = smallvec![
+        0x05,         // version
+        status as u8, // status
+        0x00,         // reserved
+    ];
+    encode_socket_addr(&mut buf, addr);
+    writer.write_all(&buf).await?;
     Ok(())
 }
 
-fn do_authenticate(auth: Auth) -> AuthResult {
-    // TODO
-    match auth {
-        Auth::None => AuthResult::Accept,
-        Auth::UsernamePassword { .. } => AuthResult::Deny,
+// Appends ATYP, address and port in the SOCKS5 wire format.
+fn encode_socket_addr(buf: &mut impl Extend<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.extend([0x01]);
+            buf.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.extend([0x04]);
+            buf.extend(ip.octets());
+        }
+    }
+    buf.extend(addr.port().to_be_bytes());
+}
+
+// Maps a failure reply from the upstream proxy to the status sent to the client.
+fn reply_to_status(reply: u8) -> Status {
+    match reply {
+        0x02 => Status::ConnectionNotAllowed,
+        0x03 => Status::NetworkUnreachable,
+        0x04 => Status::HostUnreachable,
+        0x05 => Status::ConnectionRefused,
+        0x06 => Status::TtlExpired,
+        0x07 => Status::CommandNotSupported,
+        0x08 => Status::AddressTypeNotSupported,
+        _ => Status::GeneralFailure,
     }
 }
 
 fn io_error_to_status(e: &std::io::Error) -> Status {
-    match e.raw_os_error().unwrap_or(0) {
-        // ENETUNREACH
-        101 => Status::NetworkUnreachable,
-        // ECONNREFUSED
-        111 => Status::ConnectionRefused,
-        // EHOSTUNREACH
-        113 => Status::HostUnreachable,
-        _ => Status::GeneralFailure,
+    match FailureReason::of_io(e) {
+        FailureReason::Refused => Status::ConnectionRefused,
+        FailureReason::NetworkUnreachable => Status::NetworkUnreachable,
+        FailureReason::HostUnreachable => Status::HostUnreachable,
+        // RFC 1928 calls it "TTL expired"; clients treat it as a connect timeout.
+        FailureReason::TimedOut => Status::TtlExpired,
+        FailureReason::Denied | FailureReason::Other => Status::GeneralFailure,
     }
 }
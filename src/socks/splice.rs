@@ -0,0 +1,72 @@
+// Zero-copy relaying with splice(2). Data moves from one socket into a pipe and from the
+// pipe into the other socket without passing through userspace.
+
+use std::any::Any;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+
+// Most bytes moved by one splice call, matching the default pipe capacity.
+pub(crate) const PIPE_SIZE: usize = 64 * 1024;
+
+pub(crate) struct Pipe {
+    pub read: OwnedFd,
+    pub write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and owned by us.
+        unsafe {
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+// Moves up to `len` bytes from `from` to `to`, one of which must be a pipe. Returns
+// WouldBlock instead of waiting.
+pub(crate) fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: null offsets make splice use and advance the file positions.
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// Reassembles the client stream from the halves used during the handshake, if it is a
+// TCP stream. Also returns the bytes the reader had buffered but not yet consumed.
+#[allow(clippy::type_complexity)]
+pub(crate) fn unsplit_tcp<C: AsyncRead + AsyncWrite + Unpin + 'static>(
+    reader: BufReader<ReadHalf<C>>,
+    writer: WriteHalf<C>,
+) -> Result<(TcpStream, Vec<u8>), (BufReader<ReadHalf<C>>, WriteHalf<C>)> {
+    if !(&writer as &dyn Any).is::<WriteHalf<TcpStream>>() {
+        return Err((reader, writer));
+    }
+    let buffered = reader.buffer().to_vec();
+    let client: Box<dyn Any> = Box::new(reader.into_inner().unsplit(writer));
+    let client = client
+        .downcast::<TcpStream>()
+        .expect("the client was checked to be a TcpStream");
+    Ok((*client, buffered))
+}
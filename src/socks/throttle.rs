@@ -0,0 +1,79 @@
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// BandwidthLimit caps the throughput of each TCP session.
+#[derive(Debug, Clone, Copy)]
+pub struct BandwidthLimit {
+    // Bytes per second.
+    pub rate: NonZeroU64,
+    pub scope: BandwidthScope,
+}
+
+// BandwidthScope decides whether the two directions of a session share one limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BandwidthScope {
+    // Uploads and downloads are limited separately, each to the full rate.
+    #[default]
+    PerDirection,
+    // Uploads and downloads together are limited to the rate.
+    Combined,
+}
+
+impl FromStr for BandwidthScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "per-direction" => Ok(BandwidthScope::PerDirection),
+            "combined" => Ok(BandwidthScope::Combined),
+            _ => Err(format!(
+                "unknown bandwidth scope `{s}` (expected per-direction or combined)"
+            )),
+        }
+    }
+}
+
+struct State {
+    // Goes negative when a caller takes more than is available; it then sleeps until
+    // the debt is repaid.
+    tokens: f64,
+    updated_at: Instant,
+}
+
+// TokenBucket paces a byte stream to a rate, allowing bursts of up to one second's worth.
+pub(crate) struct TokenBucket {
+    rate: f64,
+    state: Mutex<State>,
+}
+
+impl TokenBucket {
+    // A zero rate would make every wait infinite.
+    pub fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get() as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new(State {
+                tokens: rate,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    // Takes `n` bytes worth of tokens, sleeping once for as long as the bucket is short.
+    pub async fn take(&self, n: usize) {
+        let wait = {
+            let now = Instant::now();
+            let mut state = self.state.lock().unwrap();
+            let elapsed = now.duration_since(state.updated_at).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.updated_at = now;
+            state.tokens -= n as f64;
+            (state.tokens < 0.0).then(|| Duration::from_secs_f64(-state.tokens / self.rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
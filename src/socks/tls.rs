@@ -0,0 +1,37 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+
+// Tls makes the server accept clients over TLS and speak the proxy protocols inside it.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    // PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    // PEM file with the private key of the leaf certificate.
+    pub key_path: PathBuf,
+}
+
+impl Tls {
+    pub(crate) fn load_acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(&self.cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| pem_error(&self.key_path, e))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(io::Error::other)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn pem_error(path: &std::path::Path, e: rustls_pki_types::pem::Error) -> io::Error {
+    io::Error::other(format!("{}: {e}", path.display()))
+}
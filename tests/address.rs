@@ -0,0 +1,58 @@
+// Builds and converts `Address` and `PortRange` values the way embedders and tests do.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use musocks::{Address, Error, PortRange, SystemResolver};
+
+#[test]
+fn from_ip_addrs() {
+    assert_eq!(
+        Address::from(Ipv4Addr::new(192, 0, 2, 1)),
+        Address::IPv4([192, 0, 2, 1])
+    );
+    assert_eq!(
+        Address::from(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        Address::IPv6(Ipv6Addr::LOCALHOST.octets())
+    );
+    assert_eq!(
+        Address::from(Ipv4Addr::LOCALHOST).ip(),
+        Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    );
+}
+
+#[test]
+fn try_from_str() {
+    let address = Address::try_from("example.com").unwrap();
+    assert_eq!(address.domain(), Some("example.com"));
+    assert_eq!(address.ip(), None);
+    assert_eq!(
+        Address::try_from("::1").unwrap(),
+        Ipv6Addr::LOCALHOST.into()
+    );
+    assert!(matches!(
+        Address::try_from(""),
+        Err(Error::ProtocolError(_))
+    ));
+    assert!(matches!(
+        Address::try_from("bad name"),
+        Err(Error::ProtocolError(_))
+    ));
+}
+
+#[tokio::test]
+async fn to_socket_addrs() {
+    let address = Address::from(Ipv4Addr::LOCALHOST);
+    let addrs = address.to_socket_addrs(80, &SystemResolver).await.unwrap();
+    assert_eq!(addrs, [SocketAddr::from((Ipv4Addr::LOCALHOST, 80))]);
+}
+
+#[test]
+fn port_ranges() {
+    let range = PortRange::new(1000, 2000).unwrap();
+    assert_eq!((range.low(), range.high()), (1000, 2000));
+    assert_eq!("1000-2000".parse(), Ok(range));
+    assert!(PortRange::new(80, 80).is_ok());
+    assert!(PortRange::new(2000, 1000).is_err());
+    assert!(PortRange::new(0, 1000).is_err());
+    assert!("2000-1000".parse::<PortRange>().is_err());
+}
@@ -0,0 +1,719 @@
+// Drives the SOCKS handshakes with scripted client I/O and a stub connector, checking the
+// exact bytes sent back to the client.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use musocks::{
+    socks4, socks5, verify_password, Address, AddressFamily, AllowedIdents, Auth, AuthPolicy,
+    AuthResult, Authenticator, Connector, DomainMatcher, Error, PortPolicy, Server,
+    StaticAuthenticator, Upstream, UpstreamProxy,
+};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_test::io::Builder;
+
+// StubConnector records where a handshake asked to connect. It fails with `error` if set,
+// never completes if `stall` is set, and otherwise hands out a connection to a throwaway
+// loopback listener.
+#[derive(Default)]
+struct StubConnector {
+    error: Option<io::ErrorKind>,
+    stall: bool,
+    requested: Mutex<Vec<(String, u16)>>,
+}
+
+impl StubConnector {
+    fn failing(kind: io::ErrorKind) -> Arc<Self> {
+        Arc::new(StubConnector {
+            error: Some(kind),
+            ..Default::default()
+        })
+    }
+
+    fn stalled() -> Arc<Self> {
+        Arc::new(StubConnector {
+            stall: true,
+            ..Default::default()
+        })
+    }
+
+    fn requested(&self) -> Vec<(String, u16)> {
+        self.requested.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Connector for StubConnector {
+    async fn connect(&self, addr: &Address, port: u16, _: &Server) -> musocks::Result<TcpStream> {
+        self.requested
+            .lock()
+            .unwrap()
+            .push((addr.to_string(), port));
+        if let Some(kind) = self.error {
+            return Err(io::Error::from(kind).into());
+        }
+        if self.stall {
+            std::future::pending::<()>().await;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (stream, _) = tokio::try_join!(
+            TcpStream::connect(listener.local_addr()?),
+            listener.accept()
+        )?;
+        Ok(stream)
+    }
+}
+
+fn server(connector: Arc<StubConnector>) -> Server {
+    Server::builder().connector(connector).build()
+}
+
+// SOCKS5 reply with the given status and an unspecified IPv4 bound address.
+fn socks5_reply(status: u8) -> [u8; 10] {
+    [0x05, status, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+}
+
+#[tokio::test]
+async fn socks5_connect_ipv4() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00]) // methods: no authentication
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Vec::new();
+
+    let (request, upstream) = socks5::handshake(&mut reader, &mut writer, 1, &server)
+        .await
+        .unwrap();
+
+    let Upstream::Tcp(upstream) = upstream else {
+        panic!("CONNECT must produce a TCP upstream");
+    };
+    let bound = upstream.local_addr().unwrap();
+    let mut expected = vec![0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1];
+    expected.extend(bound.port().to_be_bytes());
+    assert_eq!(writer, expected);
+    assert_eq!(connector.requested(), [("192.0.2.1".to_owned(), 80)]);
+    assert_eq!(request.user, None);
+    assert_eq!(request.upstream_addr, Some(upstream.peer_addr().unwrap()));
+}
+
+#[tokio::test]
+async fn socks5_connect_domain() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 11];
+    request.extend(b"example.com");
+    request.extend(443u16.to_be_bytes());
+    let mut reader = BufReader::new(Builder::new().read(&[0x00]).read(&request).build());
+    let mut writer = Vec::new();
+
+    socks5::handshake(&mut reader, &mut writer, 1, &server)
+        .await
+        .unwrap();
+
+    assert_eq!(connector.requested(), [("example.com".to_owned(), 443)]);
+}
+
+#[tokio::test]
+async fn socks5_connection_refused() {
+    let server = server(StubConnector::failing(io::ErrorKind::ConnectionRefused));
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x05))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(
+        matches!(result, Err(Error::UpstreamConnect(e)) if e.kind() == io::ErrorKind::ConnectionRefused)
+    );
+}
+
+#[tokio::test]
+async fn socks5_connect_timed_out() {
+    let server = server(StubConnector::failing(io::ErrorKind::TimedOut));
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x06))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::UpstreamConnect(_))));
+}
+
+#[tokio::test]
+async fn socks5_connect_timeout_expires() {
+    let connector = StubConnector::stalled();
+    let server = Server::builder()
+        .connector(connector.clone())
+        .connect_timeout(Duration::from_millis(50))
+        .build();
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x06))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(
+        matches!(result, Err(Error::UpstreamConnect(e)) if e.kind() == io::ErrorKind::TimedOut)
+    );
+    assert_eq!(connector.requested(), [("192.0.2.1".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn socks5_connect_errors_map_to_statuses() {
+    let cases = [
+        (io::ErrorKind::ConnectionRefused, 0x05),
+        (io::ErrorKind::NetworkUnreachable, 0x03),
+        (io::ErrorKind::HostUnreachable, 0x04),
+        (io::ErrorKind::TimedOut, 0x06),
+        (io::ErrorKind::PermissionDenied, 0x01),
+        (io::ErrorKind::Other, 0x01),
+    ];
+    for (kind, status) in cases {
+        let server = server(StubConnector::failing(kind));
+        let mut reader = BufReader::new(
+            Builder::new()
+                .read(&[0x00])
+                .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+                .build(),
+        );
+        let mut writer = Vec::new();
+
+        let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+        assert!(matches!(result, Err(Error::UpstreamConnect(_))), "{kind:?}");
+        assert_eq!(writer[2..], socks5_reply(status), "{kind:?}");
+    }
+}
+
+#[tokio::test]
+async fn socks5_domain_denied() {
+    let connector = Arc::<StubConnector>::default();
+    let server = Server::builder()
+        .connector(connector.clone())
+        .denied_domains(DomainMatcher::new(vec!["*.example.com".to_owned()]))
+        .build();
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 15];
+    request.extend(b"www.example.com");
+    request.extend(443u16.to_be_bytes());
+    let mut reader = BufReader::new(Builder::new().read(&[0x00]).read(&request).build());
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x02))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::DomainDenied(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks5_port_not_allowed() {
+    let connector = Arc::<StubConnector>::default();
+    let server = Server::builder()
+        .connector(connector.clone())
+        .port_policy(PortPolicy::Allow(vec![443]))
+        .build();
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x19])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x02))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::PortNotAllowed(_, 25))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks5_unsupported_command() {
+    let server = server(Arc::default());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x09, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x07))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::UnsupportedCommand(0x09))));
+}
+
+#[tokio::test]
+async fn socks5_invalid_domains() {
+    let too_long = format!("{}a", "a.".repeat(127));
+    let domains: [&[u8]; 4] = [b"", too_long.as_bytes(), b"exa\nmple.com", b"example\0.com"];
+    for domain in domains {
+        let connector = Arc::<StubConnector>::default();
+        let server = server(connector.clone());
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+        request.extend(domain);
+        let mut reader = BufReader::new(Builder::new().read(&[0x00]).read(&request).build());
+        let mut writer = Builder::new()
+            .write(&[0x05, 0x00])
+            .write(&socks5_reply(0x08))
+            .build();
+
+        let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+        assert!(matches!(result, Err(Error::ProtocolError(_))), "{domain:?}");
+        assert!(connector.requested().is_empty());
+    }
+}
+
+#[tokio::test]
+async fn socks5_nonzero_reserved_byte() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x01, 0x01])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x01))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks5_bind_and_udp_associate_refused_with_upstream_proxy() {
+    let server = Server::builder()
+        .upstream_proxy(UpstreamProxy {
+            addr: "192.0.2.1:1080".parse().unwrap(),
+            credentials: None,
+        })
+        .build();
+    for command in [0x02, 0x03] {
+        let mut reader = BufReader::new(
+            Builder::new()
+                .read(&[0x00])
+                .read(&[0x05, command, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .build(),
+        );
+        let mut writer = Builder::new()
+            .write(&[0x05, 0x00])
+            .write(&socks5_reply(0x07))
+            .build();
+
+        let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+        assert!(matches!(result, Err(Error::UnsupportedCommand(c)) if c == command));
+    }
+}
+
+#[tokio::test]
+async fn socks5_ipv6_disabled() {
+    let connector = Arc::<StubConnector>::default();
+    let server = Server::builder()
+        .connector(connector.clone())
+        .address_family(AddressFamily::V4Only)
+        .build();
+    let mut request = vec![0x05, 0x01, 0x00, 0x04];
+    request.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+    request.extend(80u16.to_be_bytes());
+    let mut reader = BufReader::new(Builder::new().read(&[0x00]).read(&request).build());
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x08))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks5_ipv4_disabled() {
+    let connector = Arc::<StubConnector>::default();
+    let server = Server::builder()
+        .connector(connector.clone())
+        .address_family(AddressFamily::V6Only)
+        .build();
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00])
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x00])
+        .write(&socks5_reply(0x08))
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+fn server_with_user(connector: Arc<StubConnector>) -> Server {
+    let users = HashMap::from([(b"alice".to_vec(), b"secret".to_vec())]);
+    Server::builder()
+        .connector(connector)
+        .authenticator(Arc::new(StaticAuthenticator::new(users)))
+        .auth_policy(AuthPolicy::RequireAuth)
+        .build()
+}
+
+#[tokio::test]
+async fn socks5_password_auth() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server_with_user(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x02])
+            .read(b"\x01\x05alice\x06secret")
+            .read(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x50])
+            .build(),
+    );
+    let mut writer = Vec::new();
+
+    let (request, _) = socks5::handshake(&mut reader, &mut writer, 2, &server)
+        .await
+        .unwrap();
+
+    assert_eq!(writer[..4], [0x05, 0x02, 0x01, 0x00]);
+    assert_eq!(writer[4..6], [0x05, 0x00]);
+    assert_eq!(request.user.as_deref(), Some("alice"));
+}
+
+#[tokio::test]
+async fn socks5_wrong_password() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server_with_user(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x02])
+            .read(b"\x01\x05alice\x05wrong")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x02])
+        .write(&[0x01, 0xff])
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::AuthFailure)));
+    assert!(connector.requested().is_empty());
+}
+
+#[test]
+fn verify_password_compares_whole_passwords() {
+    let accepted = |expected: &[u8], given: &[u8]| {
+        matches!(verify_password(expected, given), AuthResult::Accept)
+    };
+    assert!(accepted(b"secret", b"secret"));
+    assert!(!accepted(b"secret", b"secreT"));
+    assert!(!accepted(b"secret", b"secre"));
+    assert!(!accepted(b"secret", b"secret!"));
+    assert!(!accepted(b"secret", b""));
+    assert!(accepted(b"", b""));
+}
+
+#[tokio::test]
+async fn static_authenticator_checks_each_user() {
+    let users = HashMap::from([
+        (b"alice".to_vec(), b"secret".to_vec()),
+        (b"bob".to_vec(), b"hunter2".to_vec()),
+        (b"carol".to_vec(), b"".to_vec()),
+    ]);
+    let authenticator = StaticAuthenticator::new(users);
+    let accepted = |username: &'static [u8], password: &'static [u8]| {
+        let auth = Auth::UsernamePassword { username, password };
+        let authenticator = &authenticator;
+        async move { matches!(authenticator.authenticate(auth).await, AuthResult::Accept) }
+    };
+
+    assert!(accepted(b"alice", b"secret").await);
+    assert!(accepted(b"bob", b"hunter2").await);
+    assert!(accepted(b"carol", b"").await);
+    assert!(!accepted(b"bob", b"secret").await);
+    assert!(!accepted(b"mallory", b"secret").await);
+    assert!(!matches!(
+        authenticator.authenticate(Auth::None).await,
+        AuthResult::Accept
+    ));
+}
+
+#[tokio::test]
+async fn socks5_bad_auth_version() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server_with_user(connector.clone());
+    let mut reader = BufReader::new(Builder::new().read(&[0x02]).read(&[0x05]).build());
+    let mut writer = Builder::new()
+        .write(&[0x05, 0x02])
+        .write(&[0x01, 0xff])
+        .build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks5_no_acceptable_methods() {
+    let server = server_with_user(Arc::default());
+    let mut reader = BufReader::new(Builder::new().read(&[0x00]).build());
+    let mut writer = Builder::new().write(&[0x05, 0xff]).build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 1, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+}
+
+#[tokio::test]
+async fn socks5_zero_methods() {
+    let server = server(Arc::default());
+    let mut reader = BufReader::new(Builder::new().build());
+    let mut writer = Builder::new().write(&[0x05, 0xff]).build();
+
+    let result = socks5::handshake(&mut reader, &mut writer, 0, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+}
+
+#[tokio::test]
+async fn socks4_connect() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(b"ident\0")
+            .build(),
+    );
+    // The reply echoes the requested port and address.
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5a, 0x00, 0x50, 192, 0, 2, 1])
+        .build();
+
+    let (request, _) = socks4::handshake(&mut reader, &mut writer, 0x01, &server)
+        .await
+        .unwrap();
+
+    assert_eq!(connector.requested(), [("192.0.2.1".to_owned(), 80)]);
+    assert_eq!(request.ident.as_deref(), Some("ident"));
+}
+
+#[tokio::test]
+async fn socks4a_connect_domain() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x01, 0xbb, 0, 0, 0, 1])
+            .read(b"\0example.com\0")
+            .build(),
+    );
+    // The port is echoed, but a domain does not fit in DSTIP.
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5a, 0x01, 0xbb, 0, 0, 0, 0])
+        .build();
+
+    let (request, _) = socks4::handshake(&mut reader, &mut writer, 0x01, &server)
+        .await
+        .unwrap();
+
+    assert_eq!(connector.requested(), [("example.com".to_owned(), 443)]);
+    assert_eq!(request.ident, None);
+}
+
+#[tokio::test]
+async fn socks4a_empty_domain() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 0, 0, 0, 1])
+            .read(b"\0\0")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x01, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks4_ident_without_nul() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(&[b'a'; 256])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x01, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks4a_domain_without_nul() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 0, 0, 0, 1])
+            .read(b"ident\0")
+            .read(&[b'a'; 256])
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x01, &server).await;
+
+    assert!(matches!(result, Err(Error::ProtocolError(_))));
+    assert!(connector.requested().is_empty());
+}
+
+#[tokio::test]
+async fn socks4_connect_failure() {
+    let server = server(StubConnector::failing(io::ErrorKind::TimedOut));
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(b"\0")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x01, &server).await;
+
+    assert!(
+        matches!(result, Err(Error::UpstreamConnect(e)) if e.kind() == io::ErrorKind::TimedOut)
+    );
+}
+
+#[tokio::test]
+async fn socks4_bind_not_supported() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(b"\0")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x02, &server).await;
+
+    assert!(matches!(result, Err(Error::UnsupportedCommand(0x02))));
+    assert!(connector.requested().is_empty());
+}
+
+fn server_with_idents(connector: Arc<StubConnector>) -> Server {
+    Server::builder()
+        .connector(connector)
+        .ident_authorizer(Arc::new(AllowedIdents::new(["alice".to_owned()])))
+        .build()
+}
+
+#[tokio::test]
+async fn socks4_allowed_ident() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server_with_idents(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(b"alice\0")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5a, 0x00, 0x50, 192, 0, 2, 1])
+        .build();
+
+    socks4::handshake(&mut reader, &mut writer, 0x01, &server)
+        .await
+        .unwrap();
+
+    assert_eq!(connector.requested(), [("192.0.2.1".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn socks4_denied_ident() {
+    let connector = Arc::<StubConnector>::default();
+    let server = server_with_idents(connector.clone());
+    let mut reader = BufReader::new(
+        Builder::new()
+            .read(&[0x00, 0x50, 192, 0, 2, 1])
+            .read(b"mallory\0")
+            .build(),
+    );
+    let mut writer = Builder::new()
+        .write(&[0x00, 0x5b, 0, 0, 0, 0, 0, 0])
+        .build();
+
+    let result = socks4::handshake(&mut reader, &mut writer, 0x01, &server).await;
+
+    assert!(matches!(result, Err(Error::IdentRejected(Some(ident))) if ident == "mallory"));
+    assert!(connector.requested().is_empty());
+}
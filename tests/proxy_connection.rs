@@ -0,0 +1,512 @@
+// Runs whole sessions through `proxy_connection` over an in-memory client stream.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use musocks::{
+    proxy_connection, AuthPolicy, ClientAddr, Destination, Error, NameserverResolver, Resolver,
+    Server, SessionStats, StaticAuthenticator, UpstreamProxy,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+fn client_addr() -> ClientAddr {
+    ClientAddr::Tcp("127.0.0.1:40000".parse().unwrap())
+}
+
+#[tokio::test]
+async fn relays_and_reports_session_stats() {
+    // The destination answers "pong" to "ping" and closes.
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = destination.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+    });
+
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let server = Arc::new(Server::builder().build());
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), server));
+
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut choice = [0u8; 2];
+    client.read_exact(&mut choice).await.unwrap();
+    assert_eq!(choice, [0x05, 0x00]);
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend(port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [0x05, 0x00]);
+
+    client.write_all(b"ping").await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"pong");
+    drop(client);
+
+    let stats = session.await.unwrap().unwrap();
+    assert_eq!(stats.protocol, Some("socks5"));
+    assert_eq!(stats.user, None);
+    assert_eq!(
+        stats.destination,
+        Some(Destination {
+            address: "127.0.0.1".to_owned(),
+            port,
+        })
+    );
+    assert_eq!((stats.uploaded_bytes, stats.downloaded_bytes), (4, 4));
+    assert!(stats.elapsed > Duration::ZERO);
+}
+
+// Runs a SOCKS5 CONNECT session whose destination reads `upload` and answers with
+// `download_len` bytes before closing. Returns what the client received after the reply.
+async fn relay_session(
+    server: Server,
+    upload: &'static [u8],
+    download_len: usize,
+) -> (Vec<u8>, SessionStats) {
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = destination.accept().await.unwrap();
+        let mut buf = vec![0u8; upload.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, upload);
+        let download: Vec<u8> = (0..download_len).map(|i| i as u8).collect();
+        stream.write_all(&download).await.unwrap();
+    });
+
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend(port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+    client.write_all(upload).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    drop(client);
+    (response, session.await.unwrap().unwrap())
+}
+
+#[tokio::test]
+async fn counts_bytes_in_each_direction() {
+    let (response, stats) = relay_session(Server::builder().build(), b"GET", 1 << 20).await;
+
+    assert_eq!(response.len(), 1 << 20);
+    assert!(response.iter().enumerate().all(|(i, &b)| b == i as u8));
+    assert_eq!(stats.uploaded_bytes, 3);
+    assert_eq!(stats.downloaded_bytes, 1 << 20);
+}
+
+#[tokio::test]
+async fn relays_through_a_small_buffer() {
+    // Every read fills at most 7 bytes, so the payloads take many partial reads.
+    let server = Server::builder().relay_buffer_size(7).build();
+    let (response, stats) =
+        relay_session(server, b"a request longer than the buffer", 10_000).await;
+
+    assert_eq!(response.len(), 10_000);
+    assert!(response.iter().enumerate().all(|(i, &b)| b == i as u8));
+    assert_eq!((stats.uploaded_bytes, stats.downloaded_bytes), (32, 10_000));
+}
+
+#[tokio::test]
+async fn client_half_close_still_receives_the_response() {
+    // The destination answers only once the client's request has ended, as a server
+    // reading a request body up to EOF does.
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = destination.accept().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        let response = vec![b'x'; 100_000];
+        stream.write_all(&response).await.unwrap();
+    });
+
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(
+        stream,
+        client_addr(),
+        Arc::new(Server::builder().build()),
+    ));
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend(port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+    client.write_all(b"request").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+
+    assert_eq!(response, vec![b'x'; 100_000]);
+    let stats = session.await.unwrap().unwrap();
+    assert_eq!((stats.uploaded_bytes, stats.downloaded_bytes), (7, 100_000));
+}
+
+#[tokio::test]
+async fn rejects_unknown_versions() {
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let server = Arc::new(Server::builder().build());
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), server));
+
+    client.write_all(&[0x07, 0x01]).await.unwrap();
+
+    let result = session.await.unwrap();
+    assert!(matches!(result, Err(Error::UnsupportedVersion(0x07))));
+}
+
+// Sends a SOCKS5 CONNECT to a closed local port and returns the reply code.
+async fn connect_to_closed_port(server: Server) -> u8 {
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = closed.local_addr().unwrap().port();
+    drop(closed);
+
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend(port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::UpstreamConnect(_))
+    ));
+    reply[3]
+}
+
+#[tokio::test]
+async fn stealth_errors_hide_the_cause() {
+    let server = Server::builder().stealth_errors(true).build();
+    assert_eq!(connect_to_closed_port(server).await, 0x01);
+}
+
+#[tokio::test]
+async fn stealth_errors_spare_trusted_clients() {
+    let server = Server::builder()
+        .stealth_errors(true)
+        .trusted_clients(vec!["127.0.0.0/8".parse().unwrap()])
+        .build();
+    assert_eq!(connect_to_closed_port(server).await, 0x05);
+}
+
+#[tokio::test]
+async fn unbindable_source_address_is_a_general_failure() {
+    // 192.0.2.1 (TEST-NET-1) is not assigned to this host.
+    let server = Server::builder()
+        .source_ip("192.0.2.1".parse().unwrap())
+        .build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    client
+        .write_all(&[
+            0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50,
+        ])
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+
+    assert_eq!(
+        reply,
+        [0x05, 0x00, 0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+    );
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::SourceBindFailed(..))
+    ));
+}
+
+#[tokio::test]
+async fn blocked_destination_is_not_allowed() {
+    let server = Server::builder().block_private_destinations(true).build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    client
+        .write_all(&[
+            0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50,
+        ])
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+
+    assert_eq!(
+        reply,
+        [0x05, 0x00, 0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+    );
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::DestinationBlocked(_))
+    ));
+}
+
+#[tokio::test]
+async fn passes_client_subnet_to_nameserver() {
+    // The nameserver answers 127.0.0.1 to A queries and nothing to AAAA queries, and
+    // reports the additional section of each query.
+    let nameserver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nameserver_addr = nameserver.local_addr().unwrap();
+    let (queries_tx, mut queries) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (n, from) = nameserver.recv_from(&mut buf).await.unwrap();
+            let question_end = 12 + b"\x07example\x04test\x00".len() + 4;
+            let qtype = buf[question_end - 3];
+            let _ = queries_tx.send(buf[question_end..n].to_vec());
+            let mut response = buf[..2].to_vec();
+            response.extend([0x81, 0x80, 0, 1, 0, (qtype == 1) as u8, 0, 0, 0, 0]);
+            response.extend(&buf[12..question_end]);
+            if qtype == 1 {
+                response.extend([0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            nameserver.send_to(&response, from).await.unwrap();
+        }
+    });
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+
+    let resolver = NameserverResolver::new(nameserver_addr).client_subnet("24,56".parse().unwrap());
+    let server = Server::builder().resolver(Arc::new(resolver)).build();
+    let client_addr = ClientAddr::Tcp("203.0.113.7:40000".parse().unwrap());
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let _session = tokio::spawn(proxy_connection(stream, client_addr, Arc::new(server)));
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 12];
+    request.extend(b"example.test");
+    request.extend(port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+
+    // Both queries carry the client's /24 and nothing more of its address.
+    let client_subnet = [0x00, 0x08, 0x00, 0x07, 0x00, 0x01, 24, 0, 203, 0, 113];
+    for _ in 0..2 {
+        let additional = queries.recv().await.unwrap();
+        assert!(additional.ends_with(&client_subnet), "{additional:?}");
+    }
+}
+
+// Fails every lookup, so that a test notices if the server resolves anything itself.
+struct NoResolver;
+
+#[async_trait::async_trait]
+impl Resolver for NoResolver {
+    async fn resolve(&self, domain: &str) -> std::io::Result<Vec<std::net::IpAddr>> {
+        panic!("resolved {domain} locally");
+    }
+}
+
+#[tokio::test]
+async fn remote_dns_passes_domains_to_the_upstream_proxy() {
+    // The upstream proxy accepts one CONNECT and reports the requested address.
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let requested = tokio::spawn(async move {
+        let (mut stream, _) = proxy.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[0x05, 0x00]).await.unwrap();
+        let mut request = [0u8; 5 + 12 + 2];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        request
+    });
+
+    let server = Server::builder()
+        .resolver(Arc::new(NoResolver))
+        .upstream_proxy(UpstreamProxy {
+            addr: proxy_addr,
+            credentials: None,
+        })
+        .remote_dns(true)
+        .build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let _session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 12];
+    request.extend(b"example.test");
+    request.extend(443u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+    assert_eq!(requested.await.unwrap()[..], request[3..]);
+}
+
+#[tokio::test]
+async fn remote_dns_without_upstream_proxy_fails_domains() {
+    let server = Server::builder()
+        .resolver(Arc::new(NoResolver))
+        .remote_dns(true)
+        .build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 12];
+    request.extend(b"example.test");
+    request.extend(443u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+
+    assert_eq!(reply[2..4], [0x05, 0x04]);
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::ResolveFailed(..))
+    ));
+}
+
+#[tokio::test]
+async fn slow_handshake_hits_the_deadline() {
+    let server = Server::builder()
+        .handshake_timeout(Duration::from_millis(200))
+        .build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    // Each byte comes well within the auth timeout, but the whole greeting does not.
+    for byte in [0x05, 0x01, 0x00] {
+        if client.write_all(&[byte]).await.is_err() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(80)).await;
+    }
+
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::HandshakeTimeout)
+    ));
+}
+
+#[tokio::test]
+async fn unanswered_bind_expires() {
+    let server = Server::builder()
+        .bind_timeout(Duration::from_millis(100))
+        .build();
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    client
+        .write_all(&[0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 12];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+    let port = u16::from_be_bytes([reply[10], reply[11]]);
+
+    // Nobody connects, so the second reply reports a general failure.
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[..2], [0x05, 0x01]);
+    match session.await.unwrap() {
+        Err(Error::BindTimeout(addr)) => assert_eq!(addr.port(), port),
+        result => panic!("unexpected result: {result:?}"),
+    }
+    // The temporary listener is gone.
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+}
+
+// Sends the whole client side of a session in one write, as clients that pipeline the
+// handshake do, and checks that the payload after the request reaches the destination.
+async fn pipelined_session(server: Server, handshake: &[u8]) {
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = destination.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+        buf
+    });
+
+    let (mut client, stream) = tokio::io::duplex(1024);
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), Arc::new(server)));
+    let mut request = handshake.to_vec();
+    request.extend([0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1]);
+    request.extend(port.to_be_bytes());
+    request.extend(b"ping");
+    client.write_all(&request).await.unwrap();
+
+    assert_eq!(&received.await.unwrap(), b"ping");
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    // The method choice, any auth status and the 10-byte CONNECT reply precede "pong".
+    assert!(response.ends_with(b"pong"), "{response:?}");
+    drop(client);
+    assert_eq!(session.await.unwrap().unwrap().uploaded_bytes, 4);
+}
+
+#[tokio::test]
+async fn pipelined_request_and_payload() {
+    pipelined_session(Server::builder().build(), &[0x05, 0x01, 0x00]).await;
+}
+
+#[tokio::test]
+async fn pipelined_auth_request_and_payload() {
+    let users = HashMap::from([(b"user".to_vec(), b"pass".to_vec())]);
+    let server = Server::builder()
+        .authenticator(Arc::new(StaticAuthenticator::new(users)))
+        .auth_policy(AuthPolicy::RequireAuth)
+        .build();
+    let mut handshake = vec![0x05, 0x01, 0x02, 0x01, 4];
+    handshake.extend(b"user");
+    handshake.push(4);
+    handshake.extend(b"pass");
+    pipelined_session(server, &handshake).await;
+}
+
+#[tokio::test]
+async fn pipelined_payload_reaches_upstream_over_tcp() {
+    // A TCP client takes the relay path that hands the socket to splice(2) on Linux,
+    // which must first forward what the handshake already buffered.
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = destination.local_addr().unwrap().port();
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = destination.accept().await.unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        buf
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let server = Arc::new(Server::builder().build());
+    let session = tokio::spawn(proxy_connection(stream, client_addr(), server));
+
+    let request = format!("CONNECT 127.0.0.1:{port} HTTP/1.1\r\n\r\nping");
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    assert_eq!(received.await.unwrap(), b"ping");
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 "), "{response:?}");
+    assert_eq!(session.await.unwrap().unwrap().uploaded_bytes, 4);
+}